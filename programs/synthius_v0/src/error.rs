@@ -20,4 +20,34 @@ pub enum ErrorCode {
     LoanValueTooHigh,
     #[msg("Program should not try to serialize a price account.")]
     TryToSerializePriceAccount,
+    #[msg("The requested leverage exceeds the maximum leverage.")]
+    LeverageTooHigh,
+    #[msg("The position is above the maintenance margin.")]
+    PositionNotLiquidatable,
+    #[msg("The market is disabled.")]
+    MarketDisabled,
+    #[msg("The position is open in the opposite direction.")]
+    PositionDirectionMismatch,
+    #[msg("The price confidence interval is too wide.")]
+    PriceConfidenceTooWide,
+    #[msg("The account is not a price account of the configured oracle.")]
+    InvalidOracle,
+    #[msg("Neither the oracle nor its fallback has a recent price.")]
+    OracleOffline,
+    #[msg("The market is closed.")]
+    MarketClosed,
+    #[msg("The circuit breaker of the market is tripped.")]
+    CircuitBreakerTripped,
+    #[msg("Not enough price history for the requested TWAP window.")]
+    TwapUnavailable,
+    #[msg("The execution price is worse than the acceptable price.")]
+    SlippageExceeded,
+    #[msg("The operation is paused.")]
+    Paused,
+    #[msg("The pool has outstanding shares but no assets.")]
+    PoolInsolvent,
+    #[msg("The pool needs its remaining liquidity to back open positions.")]
+    InsufficientLiquidity,
+    #[msg("The position would open below the maintenance margin.")]
+    MarginTooLow,
 }
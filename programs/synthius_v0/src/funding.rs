@@ -0,0 +1,80 @@
+use anchor_lang::prelude::*;
+
+use crate::math::BPS_DENOMINATOR;
+use crate::{ErrorCode, Position, SECONDS_PER_DAY};
+
+/// Scale of the cumulative funding index.
+pub const FUNDING_PRECISION: i128 = 1_000_000_000_000;
+
+/// Funding of a market. The side with more open interest pays the other
+/// side, and the pool, which holds the imbalance, keeps the difference.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct FundingState {
+    /// Open long notional in collateral token units.
+    pub long_open_interest: u64,
+    /// Open short notional in collateral token units.
+    pub short_open_interest: u64,
+    /// Funding paid per unit of long notional since the market was added,
+    /// scaled by `FUNDING_PRECISION`. Negative when shorts have paid more.
+    pub cumulative_index: i128,
+    pub last_update: i64,
+}
+
+impl FundingState {
+    /// Accrues funding since the last update at `max_rate_bps` per day,
+    /// scaled by the open interest imbalance.
+    pub fn accrue(&mut self, max_rate_bps: u16, now: i64) -> Result<()> {
+        let long = self.long_open_interest as i128;
+        let short = self.short_open_interest as i128;
+        let elapsed = now.saturating_sub(self.last_update) as i128;
+        if self.last_update != 0 && elapsed > 0 && long + short > 0 {
+            let delta = (max_rate_bps as i128 * FUNDING_PRECISION / BPS_DENOMINATOR)
+                .checked_mul(long - short)
+                .map(|rate| rate / (long + short))
+                .and_then(|rate| rate.checked_mul(elapsed))
+                .ok_or(error!(ErrorCode::Overflow))?
+                / SECONDS_PER_DAY as i128;
+            self.cumulative_index = self.cumulative_index
+                .checked_add(delta)
+                .ok_or(error!(ErrorCode::Overflow))?;
+        }
+        self.last_update = self.last_update.max(now);
+        Ok(())
+    }
+
+    /// Funding owed by a position of `notional` whose last settlement was at
+    /// `snapshot`; negative when the position is owed funding.
+    pub fn payment(&self, notional: u64, snapshot: i128, position: &Position) -> Result<i128> {
+        let owed = self.cumulative_index
+            .checked_sub(snapshot)
+            .and_then(|delta| delta.checked_mul(notional as i128))
+            .ok_or(error!(ErrorCode::Overflow))?
+            / FUNDING_PRECISION;
+        Ok(match position {
+            Position::Long => owed,
+            Position::Short => -owed,
+        })
+    }
+
+    pub fn open(&mut self, position: &Position, notional: u64) -> Result<()> {
+        let open_interest = match position {
+            Position::Long => &mut self.long_open_interest,
+            Position::Short => &mut self.short_open_interest,
+        };
+        *open_interest = open_interest
+            .checked_add(notional)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        Ok(())
+    }
+
+    pub fn close(&mut self, position: &Position, notional: u64) -> Result<()> {
+        let open_interest = match position {
+            Position::Long => &mut self.long_open_interest,
+            Position::Short => &mut self.short_open_interest,
+        };
+        *open_interest = open_interest
+            .checked_sub(notional)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::result_large_err)]
+
 use std::mem::size_of;
 use anchor_lang::prelude::*;
 use solana_program::account_info::AccountInfo;
@@ -7,9 +9,15 @@ use anchor_lang::solana_program::{
     instruction::Instruction, native_token::LAMPORTS_PER_SOL};
 use anchor_lang::InstructionData;
 
+pub mod math;
+pub mod funding;
+pub mod oracle;
 pub mod pyth;
-use pyth::PriceFeed;
-use pyth::AdminConfig;
+pub mod twap;
+use pyth::{AdminConfig, ConfigParams, FeeSplit};
+use funding::FundingState;
+use twap::TwapAccumulator;
+use oracle::{MarketOracle, MockOracle, MockPrice, OracleKind, PriceAction, PriceBound, PriceMode};
 
 mod error;
 use error::ErrorCode;
@@ -20,11 +28,50 @@ declare_id!("3o5VrciviJWYnB39NNfmsWTNqSa4aooXKUf5AzZMdWXu");
 pub mod synthius_v0 {
     use super::*;
 
-    pub fn initialize(ctx: Context<Initialize>, config: AdminConfig) -> Result<()> {
-        ctx.accounts.config.set_inner(config);
+    pub fn initialize(ctx: Context<Initialize>, params: ConfigParams) -> Result<()> {
+        if ctx.accounts.config.admin != Pubkey::default() {
+            return Err(error!(ErrorCode::ReInitialize));
+        }
+        params.validate()?;
+        ctx.accounts.config.set_inner(AdminConfig {
+            admin: ctx.accounts.payer.key(),
+            pending_admin: None,
+            params,
+            paused: PauseFlags::default(),
+            bump: *ctx.bumps.get("config").unwrap(),
+        });
         msg!("Initialize accounts");
         Ok(())
     }
+
+    /// Pauses or resumes operations in every market and pool.
+    pub fn set_global_pause(ctx: Context<UpdateConfig>, paused: PauseFlags) -> Result<()> {
+        ctx.accounts.config.paused = paused;
+        msg!("Set global pause");
+        Ok(())
+    }
+
+    pub fn update_config(ctx: Context<UpdateConfig>, params: ConfigParams) -> Result<()> {
+        params.validate()?;
+        ctx.accounts.config.params = params;
+        msg!("Update config");
+        Ok(())
+    }
+
+    /// First step of an admin handover; the new admin has to accept it.
+    pub fn propose_admin(ctx: Context<UpdateConfig>, new_admin: Pubkey) -> Result<()> {
+        ctx.accounts.config.pending_admin = Some(new_admin);
+        msg!("Propose admin: {}", new_admin);
+        Ok(())
+    }
+
+    pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        config.admin = ctx.accounts.pending_admin.key();
+        config.pending_admin = None;
+        msg!("Accept admin: {}", config.admin);
+        Ok(())
+    }
     
     pub fn dummy_token(ctx: Context<DummyToken>, amount: u64) -> Result<()> {
         let cpi_accounts = CpiContext::new(
@@ -39,16 +86,194 @@ pub mod synthius_v0 {
         Ok(())
     }
 
-    pub fn buy_long(ctx: Context<BuyLong>, amount: u64) -> Result<()> {
-        ctx.accounts.vault.amount += amount;
-        ctx.accounts.vault.position = Position::Long;
-        let vault = &mut (ctx.accounts.vault);
-        let price_feed = &ctx.accounts.pyth_loan_account;
-        let current_timestamp = Clock::get()?.unix_timestamp;
-        let stock_price = price_feed
-            .get_price_no_older_than(current_timestamp, 60)
-            .ok_or(error!(ErrorCode::PythOffline))?;
-        vault.price_entered = stock_price.price;
+    pub fn add_market(
+        ctx: Context<AddMarket>,
+        symbol: [u8; 16],
+        oracle: OracleKind,
+        fallback_oracle: Option<OracleKind>,
+        params: MarketParams,
+    ) -> Result<()> {
+        params.validate(&ctx.accounts.config)?;
+        oracle::load(oracle, &ctx.accounts.oracle_account)?;
+        let fallback_feed = match (fallback_oracle, &ctx.accounts.fallback_oracle_account) {
+            (Some(kind), Some(account)) => {
+                oracle::load(kind, account)?;
+                Some(account.key())
+            }
+            (None, None) => None,
+            _ => return Err(error!(ErrorCode::InvalidArgument)),
+        };
+        let market = &mut ctx.accounts.market;
+        market.symbol = symbol;
+        market.price_feed = ctx.accounts.oracle_account.key();
+        market.oracle = oracle;
+        market.fallback_feed = fallback_feed;
+        market.fallback_oracle = fallback_oracle;
+        market.collateral_mint = ctx.accounts.collateral_token_mint.key();
+        market.long_mint = ctx.accounts.long_token_mint.key();
+        market.short_mint = ctx.accounts.short_token_mint.key();
+        market.params = params;
+        market.paused = PauseFlags::default();
+        market.enabled = true;
+        market.bump = *ctx.bumps.get("market").unwrap();
+        msg!("Add market");
+        Ok(())
+    }
+
+    pub fn update_market(ctx: Context<UpdateMarket>, params: MarketParams) -> Result<()> {
+        params.validate(&ctx.accounts.config)?;
+        // Funding up to now accrues at the rate that was in force.
+        ctx.accounts.market.accrue_funding()?;
+        ctx.accounts.market.params = params;
+        msg!("Update market");
+        Ok(())
+    }
+
+    /// Pauses or resumes operations in one market on top of the global flags.
+    pub fn set_market_pause(ctx: Context<UpdateMarket>, paused: PauseFlags) -> Result<()> {
+        ctx.accounts.market.paused = paused;
+        msg!("Set market pause");
+        Ok(())
+    }
+
+    pub fn disable_market(ctx: Context<UpdateMarket>) -> Result<()> {
+        ctx.accounts.market.enabled = false;
+        msg!("Disable market");
+        Ok(())
+    }
+
+    pub fn enable_market(ctx: Context<UpdateMarket>) -> Result<()> {
+        ctx.accounts.market.enabled = true;
+        msg!("Enable market");
+        Ok(())
+    }
+
+    /// Crank that folds the oracle price into the market's TWAP and lets
+    /// anyone trip its circuit breaker as soon as the oracle jumps, since a
+    /// rejected trade can't persist the trip.
+    pub fn update_price(ctx: Context<UpdatePrice>) -> Result<()> {
+        let price = MarketOracle::load(
+            &ctx.accounts.market,
+            &ctx.accounts.oracle_account,
+            ctx.accounts.fallback_oracle_account.as_deref(),
+        )?
+        .spot_price(&ctx.accounts.market.params)?;
+        let market_key = ctx.accounts.market.key();
+        let market = &mut ctx.accounts.market;
+        let tripped = market.update_circuit_breaker(market_key, price)? != BreakerUpdate::Accepted;
+        let interval = market.params.twap_interval;
+        market.twap.update(price, Clock::get()?.unix_timestamp, interval)?;
+        market.accrue_funding()?;
+        msg!("Update price: {} tripped {}", price, tripped);
+        Ok(())
+    }
+
+    /// Returns the market's time-weighted average price over at least the
+    /// last `window` seconds.
+    pub fn twap(ctx: Context<ReadTwap>, window: u64) -> Result<u64> {
+        ctx.accounts.market.twap.twap(Clock::get()?.unix_timestamp, window)
+    }
+
+    pub fn reset_circuit_breaker(ctx: Context<UpdateMarket>) -> Result<()> {
+        ctx.accounts.market.circuit_breaker = CircuitBreaker::default();
+        msg!("Reset circuit breaker");
+        Ok(())
+    }
+
+    pub fn buy_long(
+        ctx: Context<BuyLong>,
+        position_index: u64,
+        amount: u64,
+        leverage: Option<u8>,
+        acceptable_price: Option<u64>,
+    ) -> Result<()> {
+        let config = AdminConfig::load(&ctx.accounts.config)?;
+        ctx.accounts.market.check_paused(&config, Operation::Open)?;
+        let leverage = leverage.unwrap_or(1);
+        if amount == 0 || leverage == 0 {
+            return Err(error!(ErrorCode::InvalidArgument));
+        }
+        // The global cap can be lowered below a market's after it was added.
+        if leverage > ctx.accounts.market.params.max_leverage.min(config.params.max_leverage) {
+            return Err(error!(ErrorCode::LeverageTooHigh));
+        }
+        let notional = amount
+            .checked_mul(leverage as u64)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        let fee = math::apply_bps(notional, ctx.accounts.market.params.open_fee_bps)?;
+        if fee >= amount {
+            return Err(error!(ErrorCode::InvalidArgument));
+        }
+        let quote = MarketOracle::load(
+            &ctx.accounts.market,
+            &ctx.accounts.oracle_account,
+            ctx.accounts.fallback_oracle_account.as_deref(),
+        )?
+        .price(&ctx.accounts.market, PriceAction::Open, &Position::Long)?;
+        ctx.accounts.market.observe_price(quote.spot, PriceAction::Open)?;
+        let price = quote.price;
+        Position::Long.check_acceptable_price(PriceAction::Open, price, acceptable_price)?;
+        let decimals = ctx.accounts.collateral_token_mint.decimals;
+        let maintenance_margin_bps = ctx.accounts.market.params.maintenance_margin_bps;
+        let owner = ctx.accounts.payer.key();
+        let market = ctx.accounts.market.key();
+        let bump = *ctx.bumps.get("vault").unwrap();
+        let index = position_index.to_le_bytes();
+        let seeds = &[&[b"vault", owner.as_ref(), market.as_ref(), index.as_ref(), anchor_lang::__private::bytemuck::bytes_of(&bump)][..]];
+        let collateral_mint = ctx.accounts.collateral_token_mint.key();
+        let pool_wallet_bump = *ctx.bumps.get("pool_wallet").unwrap();
+        let pool_seeds: &[&[&[u8]]] = &[&[b"pool_wallet", collateral_mint.as_ref(), &[pool_wallet_bump]]];
+        ctx.accounts.market.accrue_funding()?;
+        settle_funding(
+            &ctx.accounts.token_program,
+            &mut ctx.accounts.vault,
+            &ctx.accounts.vault_wallet,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.market.funding,
+            seeds,
+            pool_seeds,
+        )?;
+        let vault = &mut ctx.accounts.vault;
+        if vault.collateral_locked > 0 {
+            if vault.position != Position::Long {
+                return Err(error!(ErrorCode::PositionDirectionMismatch));
+            }
+            vault.price_entered = math::average_entry_price(
+                math::normalize_amount(vault.collateral_locked, decimals)?,
+                vault.price_entered,
+                math::normalize_amount(notional, decimals)?,
+                price,
+            )?;
+        } else {
+            vault.owner = owner;
+            vault.market = market;
+            vault.position_index = position_index;
+            vault.bump = bump;
+            vault.position = Position::Long;
+            vault.price_entered = price;
+        }
+        vault.amount = vault.amount
+            .checked_add(amount - fee)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        vault.collateral_locked = vault.collateral_locked
+            .checked_add(notional)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        vault.leverage = u8::try_from(vault.collateral_locked / vault.amount).unwrap_or(u8::MAX);
+        let pnl = math::pnl(
+            math::normalize_amount(vault.collateral_locked, decimals)?,
+            vault.price_entered,
+            quote.mid,
+            &Position::Long,
+        )?;
+        let equity = math::apply_pnl(vault.amount, decimals, pnl)?;
+        if equity < math::apply_bps(vault.collateral_locked, maintenance_margin_bps)? {
+            return Err(error!(ErrorCode::MarginTooLow));
+        }
+        ctx.accounts.market.funding.open(&Position::Long, notional)?;
+        // Funding settlement may have paid out of the pool wallet.
+        ctx.accounts.pool_wallet.reload()?;
+        let liquidity = ctx.accounts.pool_wallet.amount;
+        ctx.accounts.pool.reserve(notional, liquidity)?;
         let cpi_context = CpiContext::new(
             ctx.accounts.token_program.to_account_info(),
             token::Transfer {
@@ -57,31 +282,73 @@ pub mod synthius_v0 {
                 authority: ctx.accounts.payer.to_account_info(),
             },
         );
-        token::transfer(cpi_context, amount)?;
-        token::mint_to(CpiContext::new(
+        token::transfer(cpi_context, amount - fee)?;
+        let protocol_fee = ctx.accounts.pool.book_fee(&config.params.fee_split, fee)?;
+        let payer = ctx.accounts.payer.to_account_info();
+        transfer_signed(&ctx.accounts.token_program, &ctx.accounts.collateral_token_account, &ctx.accounts.pool_wallet, payer.clone(), &[], fee - protocol_fee)?;
+        transfer_signed(&ctx.accounts.token_program, &ctx.accounts.collateral_token_account, &ctx.accounts.fee_vault, payer, &[], protocol_fee)?;
+        let market = &ctx.accounts.market;
+        token::mint_to(CpiContext::new_with_signer(
             ctx.accounts.token_program.to_account_info(),
             token::MintTo {
                 mint: ctx.accounts.long_token_mint.to_account_info(),
                 to: ctx.accounts.long_token_account.to_account_info(),
-                authority: ctx.accounts.mint_authority.to_account_info(),
+                authority: market.to_account_info(),
             },
-        ), 1)?;
+            &[&[b"market", market.symbol.as_ref(), &[market.bump]]],
+        ), notional)?;
+        // Lets liquidations burn the position tokens without the owner.
+        token::approve(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Approve {
+                    to: ctx.accounts.long_token_account.to_account_info(),
+                    delegate: market.to_account_info(),
+                    authority: ctx.accounts.payer.to_account_info(),
+                },
+            ),
+            ctx.accounts.long_token_account.amount
+                .checked_add(notional)
+                .ok_or(error!(ErrorCode::Overflow))?,
+        )?;
         msg!("Buy long");
         Ok(())
     }
 
-    pub  fn sell_long(ctx: Context<SellLong>, bump: u8, signer: Pubkey) -> Result<()> {
-        let signer_pubkey = signer.key();
-        let signer = signer_pubkey.as_ref();
-        let seeds =  &[&[b"vault", signer , anchor_lang::__private::bytemuck::bytes_of(&bump)][..]];
+    pub fn sell_long(ctx: Context<SellLong>, acceptable_price: Option<u64>) -> Result<()> {
+        let config = AdminConfig::load(&ctx.accounts.config)?;
+        ctx.accounts.market.check_paused(&config, Operation::Close)?;
+        let owner = ctx.accounts.vault.owner;
+        let market = ctx.accounts.vault.market;
+        let index = ctx.accounts.vault.position_index.to_le_bytes();
+        let bump = ctx.accounts.vault.bump;
+        let seeds = &[&[b"vault", owner.as_ref(), market.as_ref(), index.as_ref(), anchor_lang::__private::bytemuck::bytes_of(&bump)][..]];
+        let notional = ctx.accounts.vault.collateral_locked;
+        let collateral_mint = ctx.accounts.collateral_token_mint.key();
+        let pool_wallet_bump = *ctx.bumps.get("pool_wallet").unwrap();
+        let pool_seeds: &[&[&[u8]]] = &[&[b"pool_wallet", collateral_mint.as_ref(), &[pool_wallet_bump]]];
+        ctx.accounts.market.accrue_funding()?;
+        settle_funding(
+            &ctx.accounts.token_program,
+            &mut ctx.accounts.vault,
+            &ctx.accounts.vault_wallet,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.market.funding,
+            seeds,
+            pool_seeds,
+        )?;
         let collateral = ctx.accounts.vault.amount;
+        let decimals = ctx.accounts.collateral_token_mint.decimals;
         let price_entered = ctx.accounts.vault.price_entered;
-        let price_feed = &ctx.accounts.pyth_loan_account;
-        let current_timestamp = Clock::get()?.unix_timestamp;
-        let stock_price = price_feed
-            .get_price_no_older_than(current_timestamp, 60)
-            .ok_or(error!(ErrorCode::PythOffline))?;
-        let remaining_collateral: i64;
+        let quote = MarketOracle::load(
+            &ctx.accounts.market,
+            &ctx.accounts.oracle_account,
+            ctx.accounts.fallback_oracle_account.as_deref(),
+        )?
+        .price(&ctx.accounts.market, PriceAction::Close, &Position::Long)?;
+        ctx.accounts.market.observe_price(quote.spot, PriceAction::Close)?;
+        let exit_price = quote.price;
+        Position::Long.check_acceptable_price(PriceAction::Close, exit_price, acceptable_price)?;
         if ctx.accounts.vault.position != Position::Long {
             return Err(error!(ErrorCode::InvalidArgument));
         }
@@ -91,90 +358,216 @@ pub mod synthius_v0 {
                 token::Burn {
                     mint: ctx.accounts.long_token_mint.to_account_info(),
                     from: ctx.accounts.long_token_account.to_account_info(),
-                    authority: ctx.accounts.mint_authority.to_account_info(),
+                    authority: ctx.accounts.payer.to_account_info(),
                 }
-            ),1)?;
-        if stock_price.price > price_entered {
-            let profit = stock_price.price - price_entered;
-            remaining_collateral = (collateral as i64) + profit;
-            msg!("You made a profit of {}!", remaining_collateral);
+            ),notional)?;
+        let value = math::normalize_amount(notional, decimals)?;
+        let pnl = math::pnl(value, price_entered, exit_price, &Position::Long)?;
+        let remaining_collateral = math::apply_pnl(collateral, decimals, pnl)?;
+        let fee = math::apply_bps(notional, ctx.accounts.market.params.close_fee_bps)?.min(remaining_collateral);
+        let return_bps = math::return_bps(price_entered, exit_price, &Position::Long)?;
+        if pnl > 0 {
+            msg!("You made a profit of {} bps!", return_bps);
         } else {
-            let loss = price_entered - stock_price.price;
-            remaining_collateral = (collateral as i64) - loss;
-            msg!("You made a loss of {}!", remaining_collateral);
+            msg!("You made a loss of {} bps!", -return_bps);
         }
 
-        let cpi_context = CpiContext::new_with_signer(
-            ctx.accounts.token_program.to_account_info(),
-            token::Transfer {
-                from: ctx.accounts.vault_wallet.to_account_info(),
-                to: ctx.accounts.collateral_token_account.to_account_info(),
-                authority: ctx.accounts.vault.to_account_info(),
-            },
+        settle_position(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault,
+            &ctx.accounts.vault_wallet,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.collateral_token_account,
             seeds,
-        );
-        token::transfer(cpi_context, 1/*remaining_collateral as u64*/)?;
+            pool_seeds,
+            collateral,
+            remaining_collateral - fee,
+        )?;
+        let protocol_fee = ctx.accounts.pool.book_fee(&config.params.fee_split, fee)?;
+        transfer_signed(
+            &ctx.accounts.token_program,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.fee_vault,
+            ctx.accounts.pool_wallet.to_account_info(),
+            pool_seeds,
+            protocol_fee,
+        )?;
+        ctx.accounts.vault.amount = 0;
+        ctx.accounts.vault.collateral_locked = 0;
+        ctx.accounts.market.funding.close(&Position::Long, notional)?;
+        ctx.accounts.pool.release(notional)?;
         Ok(())
 
     }
 
-    pub fn liquidata_long(ctx: Context<LiquidateLong>, bump: u8, signer: Pubkey) -> Result<()> {
-        let signer_pubkey = signer.key();
-        let signer = signer_pubkey.as_ref();
-        let seeds =  &[&[b"vault", signer , anchor_lang::__private::bytemuck::bytes_of(&bump)][..]];
+    pub fn liquidata_long(ctx: Context<LiquidateLong>) -> Result<()> {
+        let config = AdminConfig::load(&ctx.accounts.config)?;
+        ctx.accounts.market.check_paused(&config, Operation::Liquidate)?;
+        let owner = ctx.accounts.vault.owner;
+        let market = ctx.accounts.vault.market;
+        let index = ctx.accounts.vault.position_index.to_le_bytes();
+        let bump = ctx.accounts.vault.bump;
+        let seeds = &[&[b"vault", owner.as_ref(), market.as_ref(), index.as_ref(), anchor_lang::__private::bytemuck::bytes_of(&bump)][..]];
+        let collateral_mint = ctx.accounts.collateral_token_mint.key();
+        let pool_wallet_bump = *ctx.bumps.get("pool_wallet").unwrap();
+        let pool_seeds: &[&[&[u8]]] = &[&[b"pool_wallet", collateral_mint.as_ref(), &[pool_wallet_bump]]];
+        ctx.accounts.market.accrue_funding()?;
+        settle_funding(
+            &ctx.accounts.token_program,
+            &mut ctx.accounts.vault,
+            &ctx.accounts.vault_wallet,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.market.funding,
+            seeds,
+            pool_seeds,
+        )?;
         let collateral = ctx.accounts.vault.amount;
+        let notional = ctx.accounts.vault.collateral_locked;
+        let params = ctx.accounts.market.params.clone();
+        let decimals = ctx.accounts.collateral_token_mint.decimals;
         let price_entered = ctx.accounts.vault.price_entered;
-        let price_feed = &ctx.accounts.pyth_loan_account;
-        let current_timestamp = Clock::get()?.unix_timestamp;
-        let stock_price = price_feed
-            .get_price_no_older_than(current_timestamp, 60)
-            .ok_or(error!(ErrorCode::PythOffline))?;
-        let remaining_collateral: i64;
+        let quote = MarketOracle::load(
+            &ctx.accounts.market,
+            &ctx.accounts.oracle_account,
+            ctx.accounts.fallback_oracle_account.as_deref(),
+        )?
+        .price(&ctx.accounts.market, PriceAction::Liquidate, &Position::Long)?;
+        ctx.accounts.market.observe_price(quote.spot, PriceAction::Liquidate)?;
+        let exit_price = quote.price;
         if ctx.accounts.vault.position != Position::Long {
             return Err(error!(ErrorCode::InvalidArgument));
         }
-        token::burn(
-            CpiContext::new(
-                ctx.accounts.token_program.to_account_info(),
-                token::Burn {
-                    mint: ctx.accounts.long_token_mint.to_account_info(),
-                    from: ctx.accounts.long_token_account.to_account_info(),
-                    authority: ctx.accounts.mint_authority.to_account_info(),
-                }
-            ),1)?;
-        if stock_price.price > price_entered {
-            let profit = stock_price.price - price_entered;
-            remaining_collateral = (collateral as i64) + profit;
-            msg!("You made a profit of {}!", remaining_collateral);
-        } else {
-            let loss = price_entered - stock_price.price;
-            remaining_collateral = (collateral as i64) - loss;
-            msg!("You made a loss of {}!", remaining_collateral);
+        let value = math::normalize_amount(notional, decimals)?;
+        let pnl = math::pnl(value, price_entered, exit_price, &Position::Long)?;
+        let equity = math::apply_pnl(collateral, decimals, pnl)?;
+        let maintenance_margin = math::apply_bps(notional, params.maintenance_margin_bps)?;
+        if equity >= maintenance_margin {
+            return Err(error!(ErrorCode::PositionNotLiquidatable));
         }
+        let fee = math::apply_bps(notional, params.liquidation_fee_bps)?.min(collateral);
+        msg!("Liquidating position with equity {} below maintenance margin {}", equity, maintenance_margin);
 
-        let cpi_context = CpiContext::new_with_signer(
-            ctx.accounts.token_program.to_account_info(),
-            token::Transfer {
-                from: ctx.accounts.vault_wallet.to_account_info(),
-                to: ctx.accounts.collateral_token_account.to_account_info(),
-                authority: ctx.accounts.vault.to_account_info(),
-            },
+        seize_collateral(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault,
+            &ctx.accounts.vault_wallet,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.liquidator_token_account,
             seeds,
-        );
-        token::transfer(cpi_context, 1/*remaining_collateral as u64*/)?;
+            collateral,
+            fee,
+        )?;
+        let burned = burn_delegated(
+            &ctx.accounts.token_program,
+            &ctx.accounts.market,
+            &ctx.accounts.long_token_mint,
+            &ctx.accounts.long_token_account,
+            notional,
+        )?;
+        if burned < notional {
+            msg!("Burned {} of {} position tokens", burned, notional);
+        }
+        ctx.accounts.vault.amount = 0;
+        ctx.accounts.vault.collateral_locked = 0;
+        ctx.accounts.market.funding.close(&Position::Long, notional)?;
+        ctx.accounts.pool.release(notional)?;
         Ok(())
     }
 
-    pub fn buy_short(ctx: Context<BuyShort>, amount: u64) -> Result<()> {
-        ctx.accounts.vault.amount += amount;
-        ctx.accounts.vault.position = Position::Short;
-        let vault = &mut (ctx.accounts.vault);
-        let price_feed = &ctx.accounts.pyth_loan_account;
-        let current_timestamp = Clock::get()?.unix_timestamp;
-        let stock_price = price_feed
-            .get_price_no_older_than(current_timestamp, 60)
-            .ok_or(error!(ErrorCode::PythOffline))?;
-        vault.price_entered = stock_price.price;
+    pub fn buy_short(
+        ctx: Context<BuyShort>,
+        position_index: u64,
+        amount: u64,
+        leverage: Option<u8>,
+        acceptable_price: Option<u64>,
+    ) -> Result<()> {
+        let config = AdminConfig::load(&ctx.accounts.config)?;
+        ctx.accounts.market.check_paused(&config, Operation::Open)?;
+        let leverage = leverage.unwrap_or(1);
+        if amount == 0 || leverage == 0 {
+            return Err(error!(ErrorCode::InvalidArgument));
+        }
+        // The global cap can be lowered below a market's after it was added.
+        if leverage > ctx.accounts.market.params.max_leverage.min(config.params.max_leverage) {
+            return Err(error!(ErrorCode::LeverageTooHigh));
+        }
+        let notional = amount
+            .checked_mul(leverage as u64)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        let fee = math::apply_bps(notional, ctx.accounts.market.params.open_fee_bps)?;
+        if fee >= amount {
+            return Err(error!(ErrorCode::InvalidArgument));
+        }
+        let quote = MarketOracle::load(
+            &ctx.accounts.market,
+            &ctx.accounts.oracle_account,
+            ctx.accounts.fallback_oracle_account.as_deref(),
+        )?
+        .price(&ctx.accounts.market, PriceAction::Open, &Position::Short)?;
+        ctx.accounts.market.observe_price(quote.spot, PriceAction::Open)?;
+        let price = quote.price;
+        Position::Short.check_acceptable_price(PriceAction::Open, price, acceptable_price)?;
+        let decimals = ctx.accounts.collateral_token_mint.decimals;
+        let maintenance_margin_bps = ctx.accounts.market.params.maintenance_margin_bps;
+        let owner = ctx.accounts.payer.key();
+        let market = ctx.accounts.market.key();
+        let bump = *ctx.bumps.get("vault").unwrap();
+        let index = position_index.to_le_bytes();
+        let seeds = &[&[b"vault", owner.as_ref(), market.as_ref(), index.as_ref(), anchor_lang::__private::bytemuck::bytes_of(&bump)][..]];
+        let collateral_mint = ctx.accounts.collateral_token_mint.key();
+        let pool_wallet_bump = *ctx.bumps.get("pool_wallet").unwrap();
+        let pool_seeds: &[&[&[u8]]] = &[&[b"pool_wallet", collateral_mint.as_ref(), &[pool_wallet_bump]]];
+        ctx.accounts.market.accrue_funding()?;
+        settle_funding(
+            &ctx.accounts.token_program,
+            &mut ctx.accounts.vault,
+            &ctx.accounts.vault_wallet,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.market.funding,
+            seeds,
+            pool_seeds,
+        )?;
+        let vault = &mut ctx.accounts.vault;
+        if vault.collateral_locked > 0 {
+            if vault.position != Position::Short {
+                return Err(error!(ErrorCode::PositionDirectionMismatch));
+            }
+            vault.price_entered = math::average_entry_price(
+                math::normalize_amount(vault.collateral_locked, decimals)?,
+                vault.price_entered,
+                math::normalize_amount(notional, decimals)?,
+                price,
+            )?;
+        } else {
+            vault.owner = owner;
+            vault.market = market;
+            vault.position_index = position_index;
+            vault.bump = bump;
+            vault.position = Position::Short;
+            vault.price_entered = price;
+        }
+        vault.amount = vault.amount
+            .checked_add(amount - fee)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        vault.collateral_locked = vault.collateral_locked
+            .checked_add(notional)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        vault.leverage = u8::try_from(vault.collateral_locked / vault.amount).unwrap_or(u8::MAX);
+        let pnl = math::pnl(
+            math::normalize_amount(vault.collateral_locked, decimals)?,
+            vault.price_entered,
+            quote.mid,
+            &Position::Short,
+        )?;
+        let equity = math::apply_pnl(vault.amount, decimals, pnl)?;
+        if equity < math::apply_bps(vault.collateral_locked, maintenance_margin_bps)? {
+            return Err(error!(ErrorCode::MarginTooLow));
+        }
+        ctx.accounts.market.funding.open(&Position::Short, notional)?;
+        // Funding settlement may have paid out of the pool wallet.
+        ctx.accounts.pool_wallet.reload()?;
+        let liquidity = ctx.accounts.pool_wallet.amount;
+        ctx.accounts.pool.reserve(notional, liquidity)?;
         let cpi_context = CpiContext::new(
             ctx.accounts.token_program.to_account_info(),
             token::Transfer {
@@ -183,31 +576,73 @@ pub mod synthius_v0 {
                 authority: ctx.accounts.payer.to_account_info(),
             },
         );
-        token::transfer(cpi_context, amount)?;
-        token::mint_to(CpiContext::new(
+        token::transfer(cpi_context, amount - fee)?;
+        let protocol_fee = ctx.accounts.pool.book_fee(&config.params.fee_split, fee)?;
+        let payer = ctx.accounts.payer.to_account_info();
+        transfer_signed(&ctx.accounts.token_program, &ctx.accounts.collateral_token_account, &ctx.accounts.pool_wallet, payer.clone(), &[], fee - protocol_fee)?;
+        transfer_signed(&ctx.accounts.token_program, &ctx.accounts.collateral_token_account, &ctx.accounts.fee_vault, payer, &[], protocol_fee)?;
+        let market = &ctx.accounts.market;
+        token::mint_to(CpiContext::new_with_signer(
             ctx.accounts.token_program.to_account_info(),
             token::MintTo {
                 mint: ctx.accounts.short_token_mint.to_account_info(),
                 to: ctx.accounts.short_token_account.to_account_info(),
-                authority: ctx.accounts.mint_authority.to_account_info(),
+                authority: market.to_account_info(),
             },
-        ), 1)?;
+            &[&[b"market", market.symbol.as_ref(), &[market.bump]]],
+        ), notional)?;
+        // Lets liquidations burn the position tokens without the owner.
+        token::approve(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Approve {
+                    to: ctx.accounts.short_token_account.to_account_info(),
+                    delegate: market.to_account_info(),
+                    authority: ctx.accounts.payer.to_account_info(),
+                },
+            ),
+            ctx.accounts.short_token_account.amount
+                .checked_add(notional)
+                .ok_or(error!(ErrorCode::Overflow))?,
+        )?;
         msg!("Buy short");
         Ok(())
     }
 
-    pub fn sell_short(ctx: Context<SellShort>, bump: u8, signer: Pubkey) -> Result<()> {
-        let signer_pubkey = signer.key();
-        let signer = signer_pubkey.as_ref();
-        let seeds =  &[&[b"vault", signer , anchor_lang::__private::bytemuck::bytes_of(&bump)][..]];
+    pub fn sell_short(ctx: Context<SellShort>, acceptable_price: Option<u64>) -> Result<()> {
+        let config = AdminConfig::load(&ctx.accounts.config)?;
+        ctx.accounts.market.check_paused(&config, Operation::Close)?;
+        let owner = ctx.accounts.vault.owner;
+        let market = ctx.accounts.vault.market;
+        let index = ctx.accounts.vault.position_index.to_le_bytes();
+        let bump = ctx.accounts.vault.bump;
+        let seeds = &[&[b"vault", owner.as_ref(), market.as_ref(), index.as_ref(), anchor_lang::__private::bytemuck::bytes_of(&bump)][..]];
+        let notional = ctx.accounts.vault.collateral_locked;
+        let collateral_mint = ctx.accounts.collateral_token_mint.key();
+        let pool_wallet_bump = *ctx.bumps.get("pool_wallet").unwrap();
+        let pool_seeds: &[&[&[u8]]] = &[&[b"pool_wallet", collateral_mint.as_ref(), &[pool_wallet_bump]]];
+        ctx.accounts.market.accrue_funding()?;
+        settle_funding(
+            &ctx.accounts.token_program,
+            &mut ctx.accounts.vault,
+            &ctx.accounts.vault_wallet,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.market.funding,
+            seeds,
+            pool_seeds,
+        )?;
         let collateral = ctx.accounts.vault.amount;
+        let decimals = ctx.accounts.collateral_token_mint.decimals;
         let price_entered = ctx.accounts.vault.price_entered;
-        let price_feed = &ctx.accounts.pyth_loan_account;
-        let current_timestamp = Clock::get()?.unix_timestamp;
-        let stock_price = price_feed
-            .get_price_no_older_than(current_timestamp, 60)
-            .ok_or(error!(ErrorCode::PythOffline))?;
-        let remaining_collateral: i64;
+        let quote = MarketOracle::load(
+            &ctx.accounts.market,
+            &ctx.accounts.oracle_account,
+            ctx.accounts.fallback_oracle_account.as_deref(),
+        )?
+        .price(&ctx.accounts.market, PriceAction::Close, &Position::Short)?;
+        ctx.accounts.market.observe_price(quote.spot, PriceAction::Close)?;
+        let exit_price = quote.price;
+        Position::Short.check_acceptable_price(PriceAction::Close, exit_price, acceptable_price)?;
         if ctx.accounts.vault.position != Position::Short {
             return Err(error!(ErrorCode::InvalidArgument));
         }
@@ -217,201 +652,585 @@ pub mod synthius_v0 {
                 token::Burn {
                     mint: ctx.accounts.short_token_mint.to_account_info(),
                     from: ctx.accounts.short_token_account.to_account_info(),
-                    authority: ctx.accounts.mint_authority.to_account_info(),
+                    authority: ctx.accounts.payer.to_account_info(),
                 }
-            ),1)?;
-        if stock_price.price < price_entered {
-            let profit = price_entered - stock_price.price;
-            remaining_collateral = (collateral as i64) + profit;
-            msg!("You made a profit of {}!", remaining_collateral);
+            ),notional)?;
+        let value = math::normalize_amount(notional, decimals)?;
+        let pnl = math::pnl(value, price_entered, exit_price, &Position::Short)?;
+        let remaining_collateral = math::apply_pnl(collateral, decimals, pnl)?;
+        let fee = math::apply_bps(notional, ctx.accounts.market.params.close_fee_bps)?.min(remaining_collateral);
+        let return_bps = math::return_bps(price_entered, exit_price, &Position::Short)?;
+        if pnl > 0 {
+            msg!("You made a profit of {} bps!", return_bps);
         } else {
-            let loss = stock_price.price - price_entered;
-            remaining_collateral = (collateral as i64) - loss;
-            msg!("You made a loss of {}!", remaining_collateral);
+            msg!("You made a loss of {} bps!", -return_bps);
         }
-        let cpi_context = CpiContext::new_with_signer(
-            ctx.accounts.token_program.to_account_info(),
-            token::Transfer {
-                from: ctx.accounts.vault_wallet.to_account_info(),
-                to: ctx.accounts.collateral_token_account.to_account_info(),
-                authority: ctx.accounts.vault.to_account_info(),
-            },
+        settle_position(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault,
+            &ctx.accounts.vault_wallet,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.collateral_token_account,
             seeds,
-        );
-        token::transfer(cpi_context, 1/*remaining_collateral as u64*/)?;
+            pool_seeds,
+            collateral,
+            remaining_collateral - fee,
+        )?;
+        let protocol_fee = ctx.accounts.pool.book_fee(&config.params.fee_split, fee)?;
+        transfer_signed(
+            &ctx.accounts.token_program,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.fee_vault,
+            ctx.accounts.pool_wallet.to_account_info(),
+            pool_seeds,
+            protocol_fee,
+        )?;
+        ctx.accounts.vault.amount = 0;
+        ctx.accounts.vault.collateral_locked = 0;
+        ctx.accounts.market.funding.close(&Position::Short, notional)?;
+        ctx.accounts.pool.release(notional)?;
         Ok(())
     }
 
-    pub fn liquidata_short(ctx: Context<LiquidateShort>, bump:u8, signer: Pubkey) -> Result<()> {
-        let signer_pubkey = signer.key();
-        let signer = signer_pubkey.as_ref();
-        let seeds =  &[&[b"vault", signer , anchor_lang::__private::bytemuck::bytes_of(&bump)][..]];
+    pub fn liquidata_short(ctx: Context<LiquidateShort>) -> Result<()> {
+        let config = AdminConfig::load(&ctx.accounts.config)?;
+        ctx.accounts.market.check_paused(&config, Operation::Liquidate)?;
+        let owner = ctx.accounts.vault.owner;
+        let market = ctx.accounts.vault.market;
+        let index = ctx.accounts.vault.position_index.to_le_bytes();
+        let bump = ctx.accounts.vault.bump;
+        let seeds = &[&[b"vault", owner.as_ref(), market.as_ref(), index.as_ref(), anchor_lang::__private::bytemuck::bytes_of(&bump)][..]];
+        let collateral_mint = ctx.accounts.collateral_token_mint.key();
+        let pool_wallet_bump = *ctx.bumps.get("pool_wallet").unwrap();
+        let pool_seeds: &[&[&[u8]]] = &[&[b"pool_wallet", collateral_mint.as_ref(), &[pool_wallet_bump]]];
+        ctx.accounts.market.accrue_funding()?;
+        settle_funding(
+            &ctx.accounts.token_program,
+            &mut ctx.accounts.vault,
+            &ctx.accounts.vault_wallet,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.market.funding,
+            seeds,
+            pool_seeds,
+        )?;
         let collateral = ctx.accounts.vault.amount;
+        let notional = ctx.accounts.vault.collateral_locked;
+        let params = ctx.accounts.market.params.clone();
+        let decimals = ctx.accounts.collateral_token_mint.decimals;
         let price_entered = ctx.accounts.vault.price_entered;
-        let price_feed = &ctx.accounts.pyth_loan_account;
-        let current_timestamp = Clock::get()?.unix_timestamp;
-        let stock_price = price_feed
-            .get_price_no_older_than(current_timestamp, 60)
-            .ok_or(error!(ErrorCode::PythOffline))?;
-        let remaining_collateral: i64;
+        let quote = MarketOracle::load(
+            &ctx.accounts.market,
+            &ctx.accounts.oracle_account,
+            ctx.accounts.fallback_oracle_account.as_deref(),
+        )?
+        .price(&ctx.accounts.market, PriceAction::Liquidate, &Position::Short)?;
+        ctx.accounts.market.observe_price(quote.spot, PriceAction::Liquidate)?;
+        let exit_price = quote.price;
         if ctx.accounts.vault.position != Position::Short {
             return Err(error!(ErrorCode::InvalidArgument));
         }
+        let value = math::normalize_amount(notional, decimals)?;
+        let pnl = math::pnl(value, price_entered, exit_price, &Position::Short)?;
+        let equity = math::apply_pnl(collateral, decimals, pnl)?;
+        let maintenance_margin = math::apply_bps(notional, params.maintenance_margin_bps)?;
+        if equity >= maintenance_margin {
+            return Err(error!(ErrorCode::PositionNotLiquidatable));
+        }
+        let fee = math::apply_bps(notional, params.liquidation_fee_bps)?.min(collateral);
+        msg!("Liquidating position with equity {} below maintenance margin {}", equity, maintenance_margin);
+        seize_collateral(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault,
+            &ctx.accounts.vault_wallet,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.liquidator_token_account,
+            seeds,
+            collateral,
+            fee,
+        )?;
+        let burned = burn_delegated(
+            &ctx.accounts.token_program,
+            &ctx.accounts.market,
+            &ctx.accounts.short_token_mint,
+            &ctx.accounts.short_token_account,
+            notional,
+        )?;
+        if burned < notional {
+            msg!("Burned {} of {} position tokens", burned, notional);
+        }
+        ctx.accounts.vault.amount = 0;
+        ctx.accounts.vault.collateral_locked = 0;
+        ctx.accounts.market.funding.close(&Position::Short, notional)?;
+        ctx.accounts.pool.release(notional)?;
+        Ok(())
+    } 
+
+    pub fn decrease_position(ctx: Context<DecreasePosition>, amount: u64, acceptable_price: Option<u64>) -> Result<()> {
+        let config = AdminConfig::load(&ctx.accounts.config)?;
+        ctx.accounts.market.check_paused(&config, Operation::Close)?;
+        let owner = ctx.accounts.vault.owner;
+        let market = ctx.accounts.vault.market;
+        let index = ctx.accounts.vault.position_index.to_le_bytes();
+        let bump = ctx.accounts.vault.bump;
+        let seeds = &[&[b"vault", owner.as_ref(), market.as_ref(), index.as_ref(), anchor_lang::__private::bytemuck::bytes_of(&bump)][..]];
+        let notional = ctx.accounts.vault.collateral_locked;
+        if amount == 0 || amount > notional {
+            return Err(error!(ErrorCode::InvalidArgument));
+        }
+        let position = ctx.accounts.vault.position.clone();
+        let collateral_mint = ctx.accounts.collateral_token_mint.key();
+        let pool_wallet_bump = *ctx.bumps.get("pool_wallet").unwrap();
+        let pool_seeds: &[&[&[u8]]] = &[&[b"pool_wallet", collateral_mint.as_ref(), &[pool_wallet_bump]]];
+        ctx.accounts.market.accrue_funding()?;
+        settle_funding(
+            &ctx.accounts.token_program,
+            &mut ctx.accounts.vault,
+            &ctx.accounts.vault_wallet,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.market.funding,
+            seeds,
+            pool_seeds,
+        )?;
+        let collateral = math::pro_rata(ctx.accounts.vault.amount, amount, notional)?;
+        let decimals = ctx.accounts.collateral_token_mint.decimals;
+        let price_entered = ctx.accounts.vault.price_entered;
+        let quote = MarketOracle::load(
+            &ctx.accounts.market,
+            &ctx.accounts.oracle_account,
+            ctx.accounts.fallback_oracle_account.as_deref(),
+        )?
+        .price(&ctx.accounts.market, PriceAction::Close, &ctx.accounts.vault.position)?;
+        ctx.accounts.market.observe_price(quote.spot, PriceAction::Close)?;
+        let exit_price = quote.price;
+        ctx.accounts.vault.position.check_acceptable_price(PriceAction::Close, exit_price, acceptable_price)?;
         token::burn(
             CpiContext::new(
                 ctx.accounts.token_program.to_account_info(),
                 token::Burn {
-                    mint: ctx.accounts.short_token_mint.to_account_info(),
-                    from: ctx.accounts.short_token_account.to_account_info(),
-                    authority: ctx.accounts.mint_authority.to_account_info(),
+                    mint: ctx.accounts.position_token_mint.to_account_info(),
+                    from: ctx.accounts.position_token_account.to_account_info(),
+                    authority: ctx.accounts.payer.to_account_info(),
                 }
-            ),1)?;
-        if stock_price.price < price_entered {
-            let profit = price_entered - stock_price.price;
-            remaining_collateral = (collateral as i64) + profit;
-            msg!("You made a profit of {}!", remaining_collateral);
-        } else {
-            let loss = stock_price.price - price_entered;
-            remaining_collateral = (collateral as i64) - loss;
-            msg!("You made a loss of {}!", remaining_collateral);
-        }
-        let cpi_context = CpiContext::new_with_signer(
-            ctx.accounts.token_program.to_account_info(),
-            token::Transfer {
-                from: ctx.accounts.vault_wallet.to_account_info(),
-                to: ctx.accounts.collateral_token_account.to_account_info(),
-                authority: ctx.accounts.vault.to_account_info(),
-            },
+            ),amount)?;
+        let value = math::normalize_amount(amount, decimals)?;
+        let pnl = math::pnl(value, price_entered, exit_price, &position)?;
+        let remaining_collateral = math::apply_pnl(collateral, decimals, pnl)?;
+        let fee = math::apply_bps(amount, ctx.accounts.market.params.close_fee_bps)?.min(remaining_collateral);
+        let return_bps = math::return_bps(price_entered, exit_price, &position)?;
+        msg!("Decrease position by {} with a return of {} bps", amount, return_bps);
+
+        settle_position(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault,
+            &ctx.accounts.vault_wallet,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.collateral_token_account,
             seeds,
-        );
-        token::transfer(cpi_context, 1/*remaining_collateral as u64*/)?;
+            pool_seeds,
+            collateral,
+            remaining_collateral - fee,
+        )?;
+        let protocol_fee = ctx.accounts.pool.book_fee(&config.params.fee_split, fee)?;
+        transfer_signed(
+            &ctx.accounts.token_program,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.fee_vault,
+            ctx.accounts.pool_wallet.to_account_info(),
+            pool_seeds,
+            protocol_fee,
+        )?;
+        let vault = &mut ctx.accounts.vault;
+        vault.amount -= collateral;
+        vault.collateral_locked -= amount;
+        ctx.accounts.market.funding.close(&position, amount)?;
+        ctx.accounts.pool.release(amount)?;
+        if let Some(leverage) = vault.collateral_locked.checked_div(vault.amount) {
+            vault.leverage = u8::try_from(leverage).unwrap_or(u8::MAX);
+        }
         Ok(())
-    } 
+    }
+
+    pub fn initialize_pool(ctx: Context<InitializePool>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        pool.collateral_mint = ctx.accounts.collateral_token_mint.key();
+        pool.lp_mint = ctx.accounts.lp_token_mint.key();
+        pool.insurance_fees = 0;
+        pool.treasury_fees = 0;
+        pool.reserved = 0;
+        pool.bump = *ctx.bumps.get("pool").unwrap();
+        msg!("Initialize pool");
+        Ok(())
+    }
 
     pub fn add_liquidity(ctx: Context<AddLiquidity>, amount: u64) -> Result<()> {
+        AdminConfig::load(&ctx.accounts.config)?.paused.check(Operation::Deposit)?;
+        if amount == 0 {
+            return Err(error!(ErrorCode::InvalidArgument));
+        }
+        let nav = ctx.accounts.pool_wallet.amount;
+        let supply = ctx.accounts.lp_token_mint.supply;
+        // Shares minted against an empty wallet would let the existing,
+        // worthless shares claim part of this deposit.
+        if supply > 0 && nav == 0 {
+            return Err(error!(ErrorCode::PoolInsolvent));
+        }
+        // The first deposit locks `MINIMUM_LIQUIDITY` shares, plus one share
+        // per unit already in the wallet, so that no depositor can claim the
+        // leftover balance or inflate the share price to round others to 0.
+        let (shares, locked) = if supply == 0 {
+            let locked = nav
+                .checked_add(MINIMUM_LIQUIDITY)
+                .ok_or(error!(ErrorCode::Overflow))?;
+            (amount.saturating_sub(MINIMUM_LIQUIDITY), locked)
+        } else {
+            (math::pro_rata(amount, supply, nav)?, 0)
+        };
+        if shares == 0 {
+            return Err(error!(ErrorCode::InvalidArgument));
+        }
         let cpi_context = CpiContext::new(
             ctx.accounts.token_program.to_account_info(),
             token::Transfer {
                 from: ctx.accounts.collateral_token_account.to_account_info(),
-                to: ctx.accounts.vault_wallet.to_account_info(),
+                to: ctx.accounts.pool_wallet.to_account_info(),
                 authority: ctx.accounts.payer.to_account_info(),
             },
         );
         token::transfer(cpi_context, amount)?;
+        let pool = &ctx.accounts.pool;
+        let pool_seeds: &[&[u8]] = &[b"pool", pool.collateral_mint.as_ref(), &[pool.bump]];
+        if locked > 0 {
+            token::mint_to(CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::MintTo {
+                    mint: ctx.accounts.lp_token_mint.to_account_info(),
+                    to: ctx.accounts.locked_lp_token_account.to_account_info(),
+                    authority: pool.to_account_info(),
+                },
+                &[pool_seeds],
+            ), locked)?;
+        }
+        token::mint_to(CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            token::MintTo {
+                mint: ctx.accounts.lp_token_mint.to_account_info(),
+                to: ctx.accounts.lp_token_account.to_account_info(),
+                authority: pool.to_account_info(),
+            },
+            &[pool_seeds],
+        ), shares)?;
+        msg!("Add liquidity: {} for {} shares", amount, shares);
+        Ok(())
+    }
+
+    pub fn remove_liquidity(ctx: Context<RemoveLiquidity>, shares: u64) -> Result<()> {
+        AdminConfig::load(&ctx.accounts.config)?.paused.check(Operation::Withdraw)?;
+        if shares == 0 {
+            return Err(error!(ErrorCode::InvalidArgument));
+        }
+        let nav = ctx.accounts.pool_wallet.amount;
+        let supply = ctx.accounts.lp_token_mint.supply;
+        let amount = math::pro_rata(shares, nav, supply)?;
+        if nav.saturating_sub(amount) < ctx.accounts.pool.reserved {
+            return Err(error!(ErrorCode::InsufficientLiquidity));
+        }
+        token::burn(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                token::Burn {
+                    mint: ctx.accounts.lp_token_mint.to_account_info(),
+                    from: ctx.accounts.lp_token_account.to_account_info(),
+                    authority: ctx.accounts.payer.to_account_info(),
+                }
+            ),shares)?;
+        let collateral_mint = ctx.accounts.collateral_token_mint.key();
+        transfer_signed(
+            &ctx.accounts.token_program,
+            &ctx.accounts.pool_wallet,
+            &ctx.accounts.collateral_token_account,
+            ctx.accounts.pool_wallet.to_account_info(),
+            &[&[b"pool_wallet", collateral_mint.as_ref(), &[*ctx.bumps.get("pool_wallet").unwrap()]]],
+            amount,
+        )?;
+        msg!("Remove liquidity: {} shares for {}", shares, amount);
+        Ok(())
+    }
+
+    /// Sweeps the treasury share of the collected fees. The insurance share
+    /// stays in the fee vault.
+    pub fn collect_fees(ctx: Context<CollectFees>) -> Result<()> {
+        let amount = ctx.accounts.pool.treasury_fees;
+        let collateral_mint = ctx.accounts.collateral_token_mint.key();
+        transfer_signed(
+            &ctx.accounts.token_program,
+            &ctx.accounts.fee_vault,
+            &ctx.accounts.treasury_token_account,
+            ctx.accounts.fee_vault.to_account_info(),
+            &[&[b"fee_vault", collateral_mint.as_ref(), &[*ctx.bumps.get("fee_vault").unwrap()]]],
+            amount,
+        )?;
+        ctx.accounts.pool.treasury_fees = 0;
+        msg!("Collect fees: {}", amount);
+        Ok(())
+    }
+
+    /// Writes a price into a `MockOracle` account, creating it on first use.
+    pub fn set_mock_oracle(ctx: Context<SetMockOracle>, price: MockPrice) -> Result<()> {
+        if !cfg!(feature = "mock-oracle") {
+            return Err(error!(ErrorCode::InvalidOracle));
+        }
+        let authority = ctx.accounts.authority.key();
+        let mock_oracle = &mut ctx.accounts.mock_oracle;
+        if mock_oracle.authority == Pubkey::default() {
+            mock_oracle.authority = authority;
+        } else if mock_oracle.authority != authority {
+            return Err(error!(ErrorCode::Unauthorized));
+        }
+        let publish_time = match price.publish_time {
+            Some(publish_time) => publish_time,
+            None => Clock::get()?.unix_timestamp,
+        };
+        mock_oracle.price = MockPrice {
+            publish_time: Some(publish_time),
+            ..price
+        };
+        msg!("Set mock oracle: {}", mock_oracle.price.price);
         Ok(())
     }
 
-    pub fn trigger(ctx: Context<Trigger>, thread_id: Vec<u8>, bump: u8, signer: Pubkey) -> Result<()> {
+    pub fn trigger(ctx: Context<Trigger>, thread_id: Vec<u8>) -> Result<()> {
+        ctx.accounts.market.check_paused(&AdminConfig::load(&ctx.accounts.config)?, Operation::Liquidate)?;
         let config = &ctx.accounts.config;
-        let pyth_loan_account = &ctx.accounts.pyth_loan_account;
+        let market = &ctx.accounts.market;
+        let oracle_account = &ctx.accounts.oracle_account;
         let system_program = &ctx.accounts.system_program;
         let payer = &ctx.accounts.payer;
         let token_program = &ctx.accounts.token_program;
-        let associated_token_program = &ctx.accounts.associated_token_program;
         let collateral_token_mint = &ctx.accounts.collateral_token_mint;
-        let mint_authority = &ctx.accounts.mint_authority;
         let collateral_token_account = &ctx.accounts.collateral_token_account;
+        let pool = &ctx.accounts.pool;
         let vault = &ctx.accounts.vault;
+        let position_token_mint = &ctx.accounts.position_token_mint;
+        let position_token_account = &ctx.accounts.position_token_account;
         let vault_wallet = &ctx.accounts.vault_wallet;
-        let short_token_mint = &ctx.accounts.short_token_mint;
-        let short_token_account = &ctx.accounts.short_token_account;
-        let long_token_mint = &ctx.accounts.long_token_mint;
-        let long_token_account = &ctx.accounts.long_token_account;
+        let pool_wallet = &ctx.accounts.pool_wallet;
         let thread = &ctx.accounts.thread;
         let thread_authority = &ctx.accounts.thread_authority;
         let clockwork_program = &ctx.accounts.clockwork_program;
-        if vault.position == Position::Long{
-            let target_ix = Instruction {
+        // The thread acts as the keeper. The owner funds it, so the liquidation
+        // fee is credited back to the owner's collateral account.
+        let target_ix = if vault.position == Position::Long {
+            Instruction {
                 program_id: ID,
                 accounts: crate::accounts::LiquidateLong {
                     config: config.key(),
-                    pyth_loan_account: pyth_loan_account.key(),
-                    system_program: system_program.key(),
-                    payer: payer.key(),
+                    market: market.key(),
+                    oracle_account: oracle_account.key(),
+                    fallback_oracle_account: market.fallback_feed,
+                    liquidator: thread.key(),
+                    owner: payer.key(),
                     token_program: token_program.key(),
-                    associated_token_program: associated_token_program.key(),
-                    collateral_token_mint: collateral_token_mint.key(),
-                    mint_authority: mint_authority.key(),
-                    collateral_token_account: collateral_token_account.key(),
                     vault: vault.key(),
+                    long_token_mint: position_token_mint.key(),
+                    long_token_account: position_token_account.key(),
+                    collateral_token_mint: collateral_token_mint.key(),
+                    pool: pool.key(),
                     vault_wallet: vault_wallet.key(),
-                    long_token_mint: long_token_mint.key(),
-                    long_token_account: long_token_account.key(),
-                    thread: thread.key(),
-                    thread_authority: thread_authority.key(),
+                    pool_wallet: pool_wallet.key(),
+                    liquidator_token_account: collateral_token_account.key(),
                 }.to_account_metas(Some(true)),
-                data: crate::instruction::SellLong {bump, signer}.data(),
-            };
-            let trigger = clockwork_sdk::state::Trigger::Cron {
-                schedule: "0 * * * * *".into(),
-                skippable: true,
-            };
-            let bump = *ctx.bumps.get("thread_authority").unwrap();
-            clockwork_sdk::cpi::thread_create(
-                CpiContext::new_with_signer(
-                    clockwork_program.to_account_info(),
-                    clockwork_sdk::cpi::ThreadCreate {
-                        payer: payer.to_account_info(),
-                        system_program: system_program.to_account_info(),
-                        thread: thread.to_account_info(),
-                        authority: thread_authority.to_account_info(),
-                    },
-                    &[&[b"authority", payer.key().as_ref(), &[bump]]],
-                ),
-                LAMPORTS_PER_SOL,
-                thread_id,
-                vec![target_ix.into()],
-                trigger,
-            )?;
+                data: crate::instruction::LiquidataLong {}.data(),
+            }
         } else {
-            let target_ix = Instruction {
+            Instruction {
                 program_id: ID,
                 accounts: crate::accounts::LiquidateShort {
                     config: config.key(),
-                    pyth_loan_account: pyth_loan_account.key(),
-                    system_program: system_program.key(),
-                    payer: payer.key(),
+                    market: market.key(),
+                    oracle_account: oracle_account.key(),
+                    fallback_oracle_account: market.fallback_feed,
+                    liquidator: thread.key(),
+                    owner: payer.key(),
                     token_program: token_program.key(),
-                    associated_token_program: associated_token_program.key(),
-                    collateral_token_mint: collateral_token_mint.key(),
-                    mint_authority: mint_authority.key(),
-                    collateral_token_account: collateral_token_account.key(),
                     vault: vault.key(),
+                    short_token_mint: position_token_mint.key(),
+                    short_token_account: position_token_account.key(),
+                    collateral_token_mint: collateral_token_mint.key(),
+                    pool: pool.key(),
                     vault_wallet: vault_wallet.key(),
-                    short_token_mint: short_token_mint.key(),
-                    short_token_account: short_token_account.key(),
-                    thread: thread.key(),
-                    thread_authority: thread_authority.key(),
+                    pool_wallet: pool_wallet.key(),
+                    liquidator_token_account: collateral_token_account.key(),
                 }.to_account_metas(Some(true)),
-                data: crate::instruction::SellShort {bump, signer}.data(),
-            };
-            let trigger = clockwork_sdk::state::Trigger::Cron {
-                schedule: "0 * * * * *".into(),
-                skippable: true,
-            };
-            let bump = *ctx.bumps.get("thread_authority").unwrap();
-            clockwork_sdk::cpi::thread_create(
-                CpiContext::new_with_signer(
-                    clockwork_program.to_account_info(),
-                    clockwork_sdk::cpi::ThreadCreate {
-                        payer: payer.to_account_info(),
-                        system_program: system_program.to_account_info(),
-                        thread: thread.to_account_info(),
-                        authority: thread_authority.to_account_info(),
-                    },
-                    &[&[b"authority", payer.key().as_ref(), &[bump]]],
-                ),
-                LAMPORTS_PER_SOL,
-                thread_id,
-                vec![target_ix.into()],
-                trigger,
-            )?;
+                data: crate::instruction::LiquidataShort {}.data(),
+            }
         };
-
+        let trigger = clockwork_sdk::state::Trigger::Cron {
+            schedule: "0 * * * * *".into(),
+            skippable: true,
+        };
+        let bump = *ctx.bumps.get("thread_authority").unwrap();
+        clockwork_sdk::cpi::thread_create(
+            CpiContext::new_with_signer(
+                clockwork_program.to_account_info(),
+                clockwork_sdk::cpi::ThreadCreate {
+                    payer: payer.to_account_info(),
+                    system_program: system_program.to_account_info(),
+                    thread: thread.to_account_info(),
+                    authority: thread_authority.to_account_info(),
+                },
+                &[&[b"authority", payer.key().as_ref(), &[bump]]],
+            ),
+            LAMPORTS_PER_SOL,
+            thread_id,
+            vec![target_ix.into()],
+            trigger,
+        )?;
         Ok(())
     }
+}
+
+fn transfer_signed<'info>(
+    token_program: &Program<'info, token::Token>,
+    from: &Account<'info, token::TokenAccount>,
+    to: &Account<'info, token::TokenAccount>,
+    authority: AccountInfo<'info>,
+    seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            token::Transfer {
+                from: from.to_account_info(),
+                to: to.to_account_info(),
+                authority,
+            },
+            seeds,
+        ),
+        amount,
+    )
+}
+
+/// Pays out a closed position. The owner receives `payout` (collateral plus or
+/// minus PnL, floored at zero); the difference to the deposited collateral is
+/// moved from the vault wallet into the pool, or paid by the pool on a profit.
+#[allow(clippy::too_many_arguments)]
+fn settle_position<'info>(
+    token_program: &Program<'info, token::Token>,
+    vault: &Account<'info, Vault>,
+    vault_wallet: &Account<'info, token::TokenAccount>,
+    pool_wallet: &Account<'info, token::TokenAccount>,
+    collateral_token_account: &Account<'info, token::TokenAccount>,
+    vault_seeds: &[&[&[u8]]],
+    pool_seeds: &[&[&[u8]]],
+    collateral: u64,
+    payout: u64,
+) -> Result<()> {
+    if payout > collateral {
+        transfer_signed(token_program, vault_wallet, collateral_token_account, vault.to_account_info(), vault_seeds, collateral)?;
+        transfer_signed(token_program, pool_wallet, collateral_token_account, pool_wallet.to_account_info(), pool_seeds, payout - collateral)?;
+    } else {
+        transfer_signed(token_program, vault_wallet, collateral_token_account, vault.to_account_info(), vault_seeds, payout)?;
+        transfer_signed(token_program, vault_wallet, pool_wallet, vault.to_account_info(), vault_seeds, collateral - payout)?;
+    }
+    Ok(())
+}
+
+/// Burns up to `amount` position tokens from `token_account` as the market,
+/// the delegate buys approve. The owner can revoke the delegation, move the
+/// tokens or close the account, so this never fails a liquidation and
+/// returns how many tokens it burned.
+fn burn_delegated<'info>(
+    token_program: &Program<'info, token::Token>,
+    market: &Account<'info, Market>,
+    mint: &Account<'info, token::Mint>,
+    token_account: &UncheckedAccount<'info>,
+    amount: u64,
+) -> Result<u64> {
+    if token_account.owner != &token::ID || token_account.data_is_empty() {
+        return Ok(0);
+    }
+    let burned = {
+        let data = token_account.try_borrow_data()?;
+        let account = token::TokenAccount::try_deserialize(&mut &data[..])?;
+        if account.delegate != Some(market.key()).into() || account.is_frozen() {
+            return Ok(0);
+        }
+        amount.min(account.amount).min(account.delegated_amount)
+    };
+    if burned == 0 {
+        return Ok(0);
+    }
+    token::burn(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            token::Burn {
+                mint: mint.to_account_info(),
+                from: token_account.to_account_info(),
+                authority: market.to_account_info(),
+            },
+            &[&[b"market", market.symbol.as_ref(), &[market.bump]]],
+        ),
+        burned,
+    )?;
+    Ok(burned)
+}
 
+/// Settles the funding a position accrued since its last settlement against
+/// the pool and moves its snapshot to the current index. A position never
+/// pays more than its collateral, and the pool never pays more than the
+/// pool wallet holds, so closes and liquidations don't depend on pool
+/// liquidity. Call it before any other transfer from the pool wallet, whose
+/// balance it reads.
+fn settle_funding<'info>(
+    token_program: &Program<'info, token::Token>,
+    vault: &mut Account<'info, Vault>,
+    vault_wallet: &Account<'info, token::TokenAccount>,
+    pool_wallet: &Account<'info, token::TokenAccount>,
+    funding: &FundingState,
+    vault_seeds: &[&[&[u8]]],
+    pool_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let owed = funding.payment(vault.collateral_locked, vault.funding_index, &vault.position)?;
+    if owed > 0 {
+        let paid = u64::try_from(owed).unwrap_or(u64::MAX).min(vault.amount);
+        transfer_signed(token_program, vault_wallet, pool_wallet, vault.to_account_info(), vault_seeds, paid)?;
+        vault.amount -= paid;
+    } else {
+        let received = u64::try_from(-owed).unwrap_or(u64::MAX).min(pool_wallet.amount);
+        if (received as i128) < -owed {
+            msg!("Pool short of funding: paid {} of {}", received, -owed);
+        }
+        transfer_signed(token_program, pool_wallet, vault_wallet, pool_wallet.to_account_info(), pool_seeds, received)?;
+        vault.amount = vault.amount
+            .checked_add(received)
+            .ok_or(error!(ErrorCode::Overflow))?;
+    }
+    if owed != 0 {
+        msg!("Settle funding: {}", owed);
+    }
+    vault.funding_index = funding.cumulative_index;
+    Ok(())
+}
 
+/// Seizes the collateral of a liquidated position: `fee` goes to the liquidator
+/// and the remainder is routed to the pool.
+#[allow(clippy::too_many_arguments)]
+fn seize_collateral<'info>(
+    token_program: &Program<'info, token::Token>,
+    vault: &Account<'info, Vault>,
+    vault_wallet: &Account<'info, token::TokenAccount>,
+    pool_wallet: &Account<'info, token::TokenAccount>,
+    liquidator_token_account: &Account<'info, token::TokenAccount>,
+    vault_seeds: &[&[&[u8]]],
+    collateral: u64,
+    fee: u64,
+) -> Result<()> {
+    transfer_signed(token_program, vault_wallet, liquidator_token_account, vault.to_account_info(), vault_seeds, fee)?;
+    transfer_signed(token_program, vault_wallet, pool_wallet, vault.to_account_info(), vault_seeds, collateral - fee)
 }
 
 #[derive(Accounts)]
@@ -420,14 +1239,29 @@ pub struct Initialize<'info> {
     pub program: Signer<'info>,
     #[account(mut)]
     pub payer: Signer<'info>,
-    #[account(init_if_needed, payer = payer, space = 8 + size_of::<AdminConfig>())]
+    #[account(init_if_needed, payer = payer, space = 8 + size_of::<AdminConfig>(), seeds = [b"config".as_ref()], bump)]
     pub config: Account<'info, AdminConfig>,
     pub system_program: Program<'info, System>,
-    #[account(init_if_needed, payer = payer, space = 8 + size_of::<Vault>(), seeds = [b"vault".as_ref(), payer.key.as_ref()], bump)]
-    pub vault: Account<'info, Vault>,
 }
 
 
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    pub admin: Signer<'info>,
+    #[account(mut, seeds = [b"config".as_ref()], bump = config.bump, has_one = admin @ ErrorCode::Unauthorized)]
+    pub config: Account<'info, AdminConfig>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info> {
+    pub pending_admin: Signer<'info>,
+    #[account(mut,
+        seeds = [b"config".as_ref()], bump = config.bump,
+        constraint = config.pending_admin == Some(pending_admin.key()) @ ErrorCode::Unauthorized
+    )]
+    pub config: Account<'info, AdminConfig>,
+}
+
 #[derive(Accounts)]
 pub struct DummyToken<'info> {
     #[account(mut)]
@@ -443,23 +1277,94 @@ pub struct DummyToken<'info> {
 }
 
 #[derive(Accounts)]
-pub struct BuyLong<'info> {
+#[instruction(symbol: [u8; 16])]
+pub struct AddMarket<'info> {
+    pub admin: Signer<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    #[account(seeds = [b"config".as_ref()], bump = config.bump, has_one = admin @ ErrorCode::Unauthorized)]
+    pub config: Account<'info, AdminConfig>,
+    #[account(init, payer = payer, space = 8 + size_of::<Market>(), seeds = [b"market".as_ref(), symbol.as_ref()], bump)]
+    pub market: Box<Account<'info, Market>>,
+    /// CHECK: validated by `oracle::load` against the `oracle` argument
+    pub oracle_account: UncheckedAccount<'info>,
+    /// CHECK: validated by `oracle::load` against the `fallback_oracle` argument
+    pub fallback_oracle_account: Option<UncheckedAccount<'info>>,
+    pub collateral_token_mint: Account<'info, token::Mint>,
+    #[account(init,
+        payer = payer,
+        mint::decimals = collateral_token_mint.decimals,
+        mint::authority = market,
+        seeds = [b"long_mint".as_ref(), market.key().as_ref()],bump
+    )]
+    pub long_token_mint: Account<'info, token::Mint>,
+    #[account(init,
+        payer = payer,
+        mint::decimals = collateral_token_mint.decimals,
+        mint::authority = market,
+        seeds = [b"short_mint".as_ref(), market.key().as_ref()],bump
+    )]
+    pub short_token_mint: Account<'info, token::Mint>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, token::Token>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateMarket<'info> {
+    pub admin: Signer<'info>,
+    #[account(seeds = [b"config".as_ref()], bump = config.bump, has_one = admin @ ErrorCode::Unauthorized)]
     pub config: Account<'info, AdminConfig>,
-    #[account(address = config.loan_price_feed_id @ ErrorCode::InvalidArgument)]
-    pub pyth_loan_account: Account<'info, PriceFeed>,
+    #[account(mut)]
+    pub market: Box<Account<'info, Market>>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePrice<'info> {
+    #[account(mut)]
+    pub market: Box<Account<'info, Market>>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's oracle
+    #[account(address = market.price_feed @ ErrorCode::InvalidArgument)]
+    pub oracle_account: UncheckedAccount<'info>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's fallback oracle
+    #[account(constraint = market.fallback_feed == Some(fallback_oracle_account.key()) @ ErrorCode::InvalidArgument)]
+    pub fallback_oracle_account: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct ReadTwap<'info> {
+    pub market: Box<Account<'info, Market>>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_index: u64)]
+pub struct BuyLong<'info> {
+    /// CHECK: the config PDA, loaded by `AdminConfig::load`
+    #[account(seeds = [b"config".as_ref()], bump)]
+    pub config: UncheckedAccount<'info>,
+    #[account(mut, constraint = market.enabled @ ErrorCode::MarketDisabled)]
+    pub market: Box<Account<'info, Market>>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's oracle
+    #[account(address = market.price_feed @ ErrorCode::InvalidArgument)]
+    pub oracle_account: UncheckedAccount<'info>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's fallback oracle
+    #[account(constraint = market.fallback_feed == Some(fallback_oracle_account.key()) @ ErrorCode::InvalidArgument)]
+    pub fallback_oracle_account: Option<UncheckedAccount<'info>>,
     #[account(mut)]
     pub payer: Signer<'info>,
     pub system_program: Program<'info, System>,
     pub token_program: Program<'info, token::Token>,
     pub associated_token_program: Program<'info, associated_token::AssociatedToken>,
-    #[account(init, payer = payer, mint::decimals = 9, mint::authority = mint_authority)]
+    #[account(mut, address = market.long_mint @ ErrorCode::InvalidArgument)]
     pub long_token_mint: Account<'info, token::Mint>,
-    pub mint_authority: SystemAccount<'info>,
-    #[account(init, payer = payer, associated_token::mint = long_token_mint, associated_token::authority = payer)]
+    #[account(init_if_needed, payer = payer, associated_token::mint = long_token_mint, associated_token::authority = payer)]
     pub long_token_account: Account<'info, token::TokenAccount>,
-    #[account(mut, seeds = [b"vault".as_ref(), payer.key.as_ref()], bump)]
-    pub vault: Account<'info, Vault>,
-    #[account(mut)]
+    #[account(init_if_needed,
+        payer = payer,
+        space = 8 + size_of::<Vault>(),
+        seeds = [b"vault".as_ref(), payer.key.as_ref(), market.key().as_ref(), position_index.to_le_bytes().as_ref()],bump
+    )]
+    pub vault: Box<Account<'info, Vault>>,
+    #[account(address = market.collateral_mint @ ErrorCode::InvalidArgument)]
     pub collateral_token_mint: Account<'info, token::Mint>,
     #[account(mut, associated_token::mint = collateral_token_mint, associated_token::authority = payer)]
     pub collateral_token_account: Account<'info, token::TokenAccount>,
@@ -467,91 +1372,155 @@ pub struct BuyLong<'info> {
         payer = payer,
         token::mint = collateral_token_mint,
         token::authority = vault,
-        seeds = [b"vault_wallet".as_ref(), payer.key.as_ref()],bump
+        seeds = [b"vault_wallet".as_ref(), vault.key().as_ref()],bump
     )]
     pub vault_wallet: Account<'info, token::TokenAccount>,
+    #[account(mut,
+        token::mint = collateral_token_mint,
+        token::authority = pool_wallet,
+        seeds = [b"pool_wallet".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub pool_wallet: Account<'info, token::TokenAccount>,
+    #[account(mut, seeds = [b"pool".as_ref(), collateral_token_mint.key().as_ref()], bump = pool.bump)]
+    pub pool: Box<Account<'info, Pool>>,
+    #[account(mut,
+        token::mint = collateral_token_mint,
+        token::authority = fee_vault,
+        seeds = [b"fee_vault".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub fee_vault: Account<'info, token::TokenAccount>,
 }
 
 #[derive(Accounts)]
 pub struct SellLong<'info> {
-    pub config: Account<'info, AdminConfig>,
-    #[account(address = config.loan_price_feed_id @ ErrorCode::InvalidArgument)]
-    pub pyth_loan_account: Account<'info, PriceFeed>,
+    /// CHECK: the config PDA, loaded by `AdminConfig::load`
+    #[account(seeds = [b"config".as_ref()], bump)]
+    pub config: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub market: Box<Account<'info, Market>>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's oracle
+    #[account(address = market.price_feed @ ErrorCode::InvalidArgument)]
+    pub oracle_account: UncheckedAccount<'info>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's fallback oracle
+    #[account(constraint = market.fallback_feed == Some(fallback_oracle_account.key()) @ ErrorCode::InvalidArgument)]
+    pub fallback_oracle_account: Option<UncheckedAccount<'info>>,
     #[account(mut)]
     pub payer: Signer<'info>,
     pub system_program: Program<'info, System>,
     pub token_program: Program<'info, token::Token>,
     pub associated_token_program: Program<'info, associated_token::AssociatedToken>,
-    #[account(mut, seeds = [b"vault".as_ref(), payer.key.as_ref()], bump)]
-    pub vault: Account<'info, Vault>,
-    #[account(mut)]
+    #[account(mut,
+        seeds = [b"vault".as_ref(), payer.key.as_ref(), market.key().as_ref(), vault.position_index.to_le_bytes().as_ref()],
+        bump = vault.bump
+    )]
+    pub vault: Box<Account<'info, Vault>>,
+    #[account(mut, address = market.long_mint @ ErrorCode::InvalidArgument)]
     pub long_token_mint: Account<'info, token::Mint>,
-    pub mint_authority: SystemAccount<'info>,
     #[account(mut, associated_token::mint = long_token_mint, associated_token::authority = payer)]
     pub long_token_account: Account<'info, token::TokenAccount>,
-    #[account(mut)]
+    #[account(address = market.collateral_mint @ ErrorCode::InvalidArgument)]
     pub collateral_token_mint: Account<'info, token::Mint>,
     #[account(mut, associated_token::mint = collateral_token_mint, associated_token::authority = payer)]
     pub collateral_token_account: Account<'info, token::TokenAccount>,
     #[account(mut,
         token::mint = collateral_token_mint,
         token::authority = vault,
-        seeds = [b"vault_wallet".as_ref(), payer.key.as_ref()],bump
+        seeds = [b"vault_wallet".as_ref(), vault.key().as_ref()],bump
     )]
     pub vault_wallet: Account<'info, token::TokenAccount>,
+    #[account(mut,
+        token::mint = collateral_token_mint,
+        token::authority = pool_wallet,
+        seeds = [b"pool_wallet".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub pool_wallet: Account<'info, token::TokenAccount>,
+    #[account(mut, seeds = [b"pool".as_ref(), collateral_token_mint.key().as_ref()], bump = pool.bump)]
+    pub pool: Box<Account<'info, Pool>>,
+    #[account(mut,
+        token::mint = collateral_token_mint,
+        token::authority = fee_vault,
+        seeds = [b"fee_vault".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub fee_vault: Account<'info, token::TokenAccount>,
 }
 
 #[derive(Accounts)]
 pub struct LiquidateLong<'info> {
-    pub config: Account<'info, AdminConfig>,
-    #[account(address = config.loan_price_feed_id @ ErrorCode::InvalidArgument)]
-    pub pyth_loan_account: Account<'info, PriceFeed>,
+    /// CHECK: the config PDA, loaded by `AdminConfig::load`
+    #[account(seeds = [b"config".as_ref()], bump)]
+    pub config: UncheckedAccount<'info>,
     #[account(mut)]
-    pub payer: Signer<'info>,
-    pub system_program: Program<'info, System>,
+    pub market: Box<Account<'info, Market>>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's oracle
+    #[account(address = market.price_feed @ ErrorCode::InvalidArgument)]
+    pub oracle_account: UncheckedAccount<'info>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's fallback oracle
+    #[account(constraint = market.fallback_feed == Some(fallback_oracle_account.key()) @ ErrorCode::InvalidArgument)]
+    pub fallback_oracle_account: Option<UncheckedAccount<'info>>,
+    pub liquidator: Signer<'info>,
+    pub owner: SystemAccount<'info>,
     pub token_program: Program<'info, token::Token>,
-    pub associated_token_program: Program<'info, associated_token::AssociatedToken>,
-    #[account(mut, seeds = [b"vault".as_ref(), payer.key.as_ref()], bump)]
-    pub vault: Account<'info, Vault>,
-    #[account(mut)]
+    #[account(mut,
+        seeds = [b"vault".as_ref(), owner.key.as_ref(), market.key().as_ref(), vault.position_index.to_le_bytes().as_ref()],
+        bump = vault.bump
+    )]
+    pub vault: Box<Account<'info, Vault>>,
+    #[account(mut, address = market.long_mint @ ErrorCode::InvalidArgument)]
     pub long_token_mint: Account<'info, token::Mint>,
-    pub mint_authority: SystemAccount<'info>,
-    #[account(mut, associated_token::mint = long_token_mint, associated_token::authority = payer)]
-    pub long_token_account: Account<'info, token::TokenAccount>,
-    #[account(mut)]
+    /// CHECK: the owner's long token account, which the owner may have
+    /// closed; `burn_delegated` only burns from it if it is still usable
+    #[account(mut, address = associated_token::get_associated_token_address(owner.key, &long_token_mint.key()) @ ErrorCode::InvalidArgument)]
+    pub long_token_account: UncheckedAccount<'info>,
+    #[account(address = market.collateral_mint @ ErrorCode::InvalidArgument)]
     pub collateral_token_mint: Account<'info, token::Mint>,
-    #[account(mut, associated_token::mint = collateral_token_mint, associated_token::authority = payer)]
-    pub collateral_token_account: Account<'info, token::TokenAccount>,
+    #[account(mut, seeds = [b"pool".as_ref(), collateral_token_mint.key().as_ref()], bump = pool.bump)]
+    pub pool: Box<Account<'info, Pool>>,
     #[account(mut,
         token::mint = collateral_token_mint,
         token::authority = vault,
-        seeds = [b"vault_wallet".as_ref(), payer.key.as_ref()],bump
+        seeds = [b"vault_wallet".as_ref(), vault.key().as_ref()],bump
     )]
     pub vault_wallet: Account<'info, token::TokenAccount>,
-    #[account(signer, constraint = thread.authority.eq(&thread_authority.key()))]
-    pub thread: Account<'info, Thread>,
-    #[account(seeds = [b"authority".as_ref(), payer.key.as_ref()], bump)]
-    pub thread_authority: SystemAccount<'info>,
+    #[account(mut,
+        token::mint = collateral_token_mint,
+        token::authority = pool_wallet,
+        seeds = [b"pool_wallet".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub pool_wallet: Account<'info, token::TokenAccount>,
+    #[account(mut, token::mint = collateral_token_mint)]
+    pub liquidator_token_account: Account<'info, token::TokenAccount>,
 }
 
 #[derive(Accounts)]
+#[instruction(position_index: u64)]
 pub struct BuyShort<'info> {
-    pub config: Account<'info, AdminConfig>,
-    #[account(address = config.loan_price_feed_id @ ErrorCode::InvalidArgument)]
-    pub pyth_loan_account: Account<'info, PriceFeed>,
+    /// CHECK: the config PDA, loaded by `AdminConfig::load`
+    #[account(seeds = [b"config".as_ref()], bump)]
+    pub config: UncheckedAccount<'info>,
+    #[account(mut, constraint = market.enabled @ ErrorCode::MarketDisabled)]
+    pub market: Box<Account<'info, Market>>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's oracle
+    #[account(address = market.price_feed @ ErrorCode::InvalidArgument)]
+    pub oracle_account: UncheckedAccount<'info>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's fallback oracle
+    #[account(constraint = market.fallback_feed == Some(fallback_oracle_account.key()) @ ErrorCode::InvalidArgument)]
+    pub fallback_oracle_account: Option<UncheckedAccount<'info>>,
     #[account(mut)]
     pub payer: Signer<'info>,
     pub system_program: Program<'info, System>,
     pub token_program: Program<'info, token::Token>,
     pub associated_token_program: Program<'info, associated_token::AssociatedToken>,
-    #[account(init, payer = payer, mint::decimals = 9, mint::authority = mint_authority)]
+    #[account(mut, address = market.short_mint @ ErrorCode::InvalidArgument)]
     pub short_token_mint: Account<'info, token::Mint>,
-    pub mint_authority: SystemAccount<'info>,
-    #[account(init, payer = payer, associated_token::mint = short_token_mint, associated_token::authority = payer)]
+    #[account(init_if_needed, payer = payer, associated_token::mint = short_token_mint, associated_token::authority = payer)]
     pub short_token_account: Account<'info, token::TokenAccount>,
-    #[account(mut, seeds = [b"vault".as_ref(), payer.key.as_ref()], bump)]
-    pub vault: Account<'info, Vault>,
-    #[account(mut)]
+    #[account(init_if_needed,
+        payer = payer,
+        space = 8 + size_of::<Vault>(),
+        seeds = [b"vault".as_ref(), payer.key.as_ref(), market.key().as_ref(), position_index.to_le_bytes().as_ref()],bump
+    )]
+    pub vault: Box<Account<'info, Vault>>,
+    #[account(address = market.collateral_mint @ ErrorCode::InvalidArgument)]
     pub collateral_token_mint: Account<'info, token::Mint>,
     #[account(mut, associated_token::mint = collateral_token_mint, associated_token::authority = payer)]
     pub collateral_token_account: Account<'info, token::TokenAccount>,
@@ -559,98 +1528,308 @@ pub struct BuyShort<'info> {
         payer = payer,
         token::mint = collateral_token_mint,
         token::authority = vault,
-        seeds = [b"vault_wallet".as_ref(), payer.key.as_ref()],bump
+        seeds = [b"vault_wallet".as_ref(), vault.key().as_ref()],bump
     )]
     pub vault_wallet: Account<'info, token::TokenAccount>,
+    #[account(mut,
+        token::mint = collateral_token_mint,
+        token::authority = pool_wallet,
+        seeds = [b"pool_wallet".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub pool_wallet: Account<'info, token::TokenAccount>,
+    #[account(mut, seeds = [b"pool".as_ref(), collateral_token_mint.key().as_ref()], bump = pool.bump)]
+    pub pool: Box<Account<'info, Pool>>,
+    #[account(mut,
+        token::mint = collateral_token_mint,
+        token::authority = fee_vault,
+        seeds = [b"fee_vault".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub fee_vault: Account<'info, token::TokenAccount>,
 }
 
 #[derive(Accounts)]
 pub struct SellShort<'info> {
-    pub config: Account<'info, AdminConfig>,
-    #[account(address = config.loan_price_feed_id @ ErrorCode::InvalidArgument)]
-    pub pyth_loan_account: Account<'info, PriceFeed>,
+    /// CHECK: the config PDA, loaded by `AdminConfig::load`
+    #[account(seeds = [b"config".as_ref()], bump)]
+    pub config: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub market: Box<Account<'info, Market>>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's oracle
+    #[account(address = market.price_feed @ ErrorCode::InvalidArgument)]
+    pub oracle_account: UncheckedAccount<'info>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's fallback oracle
+    #[account(constraint = market.fallback_feed == Some(fallback_oracle_account.key()) @ ErrorCode::InvalidArgument)]
+    pub fallback_oracle_account: Option<UncheckedAccount<'info>>,
     #[account(mut)]
     pub payer: Signer<'info>,
     pub system_program: Program<'info, System>,
     pub token_program: Program<'info, token::Token>,
     pub associated_token_program: Program<'info, associated_token::AssociatedToken>,
-    #[account(mut, seeds = [b"vault".as_ref(), payer.key.as_ref()], bump)]
-    pub vault: Account<'info, Vault>,
-    #[account(mut)]
+    #[account(mut,
+        seeds = [b"vault".as_ref(), payer.key.as_ref(), market.key().as_ref(), vault.position_index.to_le_bytes().as_ref()],
+        bump = vault.bump
+    )]
+    pub vault: Box<Account<'info, Vault>>,
+    #[account(mut, address = market.short_mint @ ErrorCode::InvalidArgument)]
     pub short_token_mint: Account<'info, token::Mint>,
-    pub mint_authority: SystemAccount<'info>,
     #[account(mut, associated_token::mint = short_token_mint, associated_token::authority = payer)]
     pub short_token_account: Account<'info, token::TokenAccount>,
-    #[account(mut)]
+    #[account(address = market.collateral_mint @ ErrorCode::InvalidArgument)]
     pub collateral_token_mint: Account<'info, token::Mint>,
     #[account(mut, associated_token::mint = collateral_token_mint, associated_token::authority = payer)]
     pub collateral_token_account: Account<'info, token::TokenAccount>,
     #[account(mut,
         token::mint = collateral_token_mint,
         token::authority = vault,
-        seeds = [b"vault_wallet".as_ref(), payer.key.as_ref()],bump
+        seeds = [b"vault_wallet".as_ref(), vault.key().as_ref()],bump
     )]
     pub vault_wallet: Account<'info, token::TokenAccount>,
+    #[account(mut,
+        token::mint = collateral_token_mint,
+        token::authority = pool_wallet,
+        seeds = [b"pool_wallet".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub pool_wallet: Account<'info, token::TokenAccount>,
+    #[account(mut, seeds = [b"pool".as_ref(), collateral_token_mint.key().as_ref()], bump = pool.bump)]
+    pub pool: Box<Account<'info, Pool>>,
+    #[account(mut,
+        token::mint = collateral_token_mint,
+        token::authority = fee_vault,
+        seeds = [b"fee_vault".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub fee_vault: Account<'info, token::TokenAccount>,
 }
 
 #[derive(Accounts)]
 pub struct LiquidateShort<'info> {
-    pub config: Account<'info, AdminConfig>,
-    #[account(address = config.loan_price_feed_id @ ErrorCode::InvalidArgument)]
-    pub pyth_loan_account: Account<'info, PriceFeed>,
+    /// CHECK: the config PDA, loaded by `AdminConfig::load`
+    #[account(seeds = [b"config".as_ref()], bump)]
+    pub config: UncheckedAccount<'info>,
     #[account(mut)]
-    pub payer: Signer<'info>,
-    pub system_program: Program<'info, System>,
+    pub market: Box<Account<'info, Market>>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's oracle
+    #[account(address = market.price_feed @ ErrorCode::InvalidArgument)]
+    pub oracle_account: UncheckedAccount<'info>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's fallback oracle
+    #[account(constraint = market.fallback_feed == Some(fallback_oracle_account.key()) @ ErrorCode::InvalidArgument)]
+    pub fallback_oracle_account: Option<UncheckedAccount<'info>>,
+    pub liquidator: Signer<'info>,
+    pub owner: SystemAccount<'info>,
     pub token_program: Program<'info, token::Token>,
-    pub associated_token_program: Program<'info, associated_token::AssociatedToken>,
-    #[account(mut, seeds = [b"vault".as_ref(), payer.key.as_ref()], bump)]
-    pub vault: Account<'info, Vault>,
-    #[account(mut)]
+    #[account(mut,
+        seeds = [b"vault".as_ref(), owner.key.as_ref(), market.key().as_ref(), vault.position_index.to_le_bytes().as_ref()],
+        bump = vault.bump
+    )]
+    pub vault: Box<Account<'info, Vault>>,
+    #[account(mut, address = market.short_mint @ ErrorCode::InvalidArgument)]
     pub short_token_mint: Account<'info, token::Mint>,
-    pub mint_authority: SystemAccount<'info>,
-    #[account(mut, associated_token::mint = short_token_mint, associated_token::authority = payer)]
-    pub short_token_account: Account<'info, token::TokenAccount>,
+    /// CHECK: the owner's short token account, which the owner may have
+    /// closed; `burn_delegated` only burns from it if it is still usable
+    #[account(mut, address = associated_token::get_associated_token_address(owner.key, &short_token_mint.key()) @ ErrorCode::InvalidArgument)]
+    pub short_token_account: UncheckedAccount<'info>,
+    #[account(address = market.collateral_mint @ ErrorCode::InvalidArgument)]
+    pub collateral_token_mint: Account<'info, token::Mint>,
+    #[account(mut, seeds = [b"pool".as_ref(), collateral_token_mint.key().as_ref()], bump = pool.bump)]
+    pub pool: Box<Account<'info, Pool>>,
+    #[account(mut,
+        token::mint = collateral_token_mint,
+        token::authority = vault,
+        seeds = [b"vault_wallet".as_ref(), vault.key().as_ref()],bump
+    )]
+    pub vault_wallet: Account<'info, token::TokenAccount>,
+    #[account(mut,
+        token::mint = collateral_token_mint,
+        token::authority = pool_wallet,
+        seeds = [b"pool_wallet".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub pool_wallet: Account<'info, token::TokenAccount>,
+    #[account(mut, token::mint = collateral_token_mint)]
+    pub liquidator_token_account: Account<'info, token::TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct DecreasePosition<'info> {
+    /// CHECK: the config PDA, loaded by `AdminConfig::load`
+    #[account(seeds = [b"config".as_ref()], bump)]
+    pub config: UncheckedAccount<'info>,
     #[account(mut)]
+    pub market: Box<Account<'info, Market>>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's oracle
+    #[account(address = market.price_feed @ ErrorCode::InvalidArgument)]
+    pub oracle_account: UncheckedAccount<'info>,
+    /// CHECK: parsed by `MarketOracle::load` as the market's fallback oracle
+    #[account(constraint = market.fallback_feed == Some(fallback_oracle_account.key()) @ ErrorCode::InvalidArgument)]
+    pub fallback_oracle_account: Option<UncheckedAccount<'info>>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub token_program: Program<'info, token::Token>,
+    #[account(mut,
+        seeds = [b"vault".as_ref(), payer.key.as_ref(), market.key().as_ref(), vault.position_index.to_le_bytes().as_ref()],
+        bump = vault.bump
+    )]
+    pub vault: Box<Account<'info, Vault>>,
+    #[account(mut, address = market.position_mint(&vault.position) @ ErrorCode::InvalidArgument)]
+    pub position_token_mint: Account<'info, token::Mint>,
+    #[account(mut, associated_token::mint = position_token_mint, associated_token::authority = payer)]
+    pub position_token_account: Account<'info, token::TokenAccount>,
+    #[account(address = market.collateral_mint @ ErrorCode::InvalidArgument)]
     pub collateral_token_mint: Account<'info, token::Mint>,
     #[account(mut, associated_token::mint = collateral_token_mint, associated_token::authority = payer)]
     pub collateral_token_account: Account<'info, token::TokenAccount>,
     #[account(mut,
         token::mint = collateral_token_mint,
         token::authority = vault,
-        seeds = [b"vault_wallet".as_ref(), payer.key.as_ref()],bump
+        seeds = [b"vault_wallet".as_ref(), vault.key().as_ref()],bump
     )]
     pub vault_wallet: Account<'info, token::TokenAccount>,
-    #[account(signer,
-        constraint = thread.authority.eq(&thread_authority.key()))]
-    pub thread: Account<'info, Thread>,
-    #[account(seeds = [b"authority".as_ref(), payer.key.as_ref()], bump)]
-    pub thread_authority: SystemAccount<'info>,
+    #[account(mut,
+        token::mint = collateral_token_mint,
+        token::authority = pool_wallet,
+        seeds = [b"pool_wallet".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub pool_wallet: Account<'info, token::TokenAccount>,
+    #[account(mut, seeds = [b"pool".as_ref(), collateral_token_mint.key().as_ref()], bump = pool.bump)]
+    pub pool: Box<Account<'info, Pool>>,
+    #[account(mut,
+        token::mint = collateral_token_mint,
+        token::authority = fee_vault,
+        seeds = [b"fee_vault".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub fee_vault: Account<'info, token::TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct InitializePool<'info> {
+    pub admin: Signer<'info>,
+    #[account(seeds = [b"config".as_ref()], bump = config.bump, has_one = admin @ ErrorCode::Unauthorized)]
+    pub config: Account<'info, AdminConfig>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, token::Token>,
+    pub collateral_token_mint: Account<'info, token::Mint>,
+    #[account(init, payer = payer, space = 8 + size_of::<Pool>(), seeds = [b"pool".as_ref(), collateral_token_mint.key().as_ref()], bump)]
+    pub pool: Account<'info, Pool>,
+    #[account(init,
+        payer = payer,
+        mint::decimals = collateral_token_mint.decimals,
+        mint::authority = pool,
+        seeds = [b"lp_mint".as_ref(), pool.key().as_ref()],bump
+    )]
+    pub lp_token_mint: Account<'info, token::Mint>,
+    #[account(init,
+        payer = payer,
+        token::mint = collateral_token_mint,
+        token::authority = pool_wallet,
+        seeds = [b"pool_wallet".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub pool_wallet: Account<'info, token::TokenAccount>,
+    #[account(init,
+        payer = payer,
+        token::mint = collateral_token_mint,
+        token::authority = fee_vault,
+        seeds = [b"fee_vault".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub fee_vault: Account<'info, token::TokenAccount>,
+    #[account(init,
+        payer = payer,
+        token::mint = lp_token_mint,
+        token::authority = pool,
+        seeds = [b"locked_lp".as_ref(), pool.key().as_ref()],bump
+    )]
+    pub locked_lp_token_account: Account<'info, token::TokenAccount>,
 }
 
 #[derive(Accounts)]
 pub struct AddLiquidity<'info> {
+    /// CHECK: the config PDA, loaded by `AdminConfig::load`
+    #[account(seeds = [b"config".as_ref()], bump)]
+    pub config: UncheckedAccount<'info>,
     #[account(mut)]
     pub payer: Signer<'info>,
     pub system_program: Program<'info, System>,
     pub token_program: Program<'info, token::Token>,
     pub associated_token_program: Program<'info, associated_token::AssociatedToken>,
+    pub collateral_token_mint: Account<'info, token::Mint>,
+    #[account(mut, associated_token::mint = collateral_token_mint, associated_token::authority = payer)]
+    pub collateral_token_account: Account<'info, token::TokenAccount>,
+    #[account(seeds = [b"pool".as_ref(), collateral_token_mint.key().as_ref()], bump = pool.bump)]
+    pub pool: Account<'info, Pool>,
+    #[account(mut, address = pool.lp_mint @ ErrorCode::InvalidArgument)]
+    pub lp_token_mint: Account<'info, token::Mint>,
+    #[account(init_if_needed, payer = payer, associated_token::mint = lp_token_mint, associated_token::authority = payer)]
+    pub lp_token_account: Account<'info, token::TokenAccount>,
+    #[account(mut,
+        token::mint = collateral_token_mint,
+        token::authority = pool_wallet,
+        seeds = [b"pool_wallet".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub pool_wallet: Account<'info, token::TokenAccount>,
+    #[account(mut, seeds = [b"locked_lp".as_ref(), pool.key().as_ref()], bump)]
+    pub locked_lp_token_account: Account<'info, token::TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveLiquidity<'info> {
+    /// CHECK: the config PDA, loaded by `AdminConfig::load`
+    #[account(seeds = [b"config".as_ref()], bump)]
+    pub config: UncheckedAccount<'info>,
     #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, token::Token>,
+    pub associated_token_program: Program<'info, associated_token::AssociatedToken>,
     pub collateral_token_mint: Account<'info, token::Mint>,
     #[account(mut, associated_token::mint = collateral_token_mint, associated_token::authority = payer)]
     pub collateral_token_account: Account<'info, token::TokenAccount>,
-    #[account(mut, seeds = [b"vault".as_ref(), payer.key.as_ref()], bump)]
-    pub vault: Account<'info, Vault>,
+    #[account(seeds = [b"pool".as_ref(), collateral_token_mint.key().as_ref()], bump = pool.bump)]
+    pub pool: Account<'info, Pool>,
+    #[account(mut, address = pool.lp_mint @ ErrorCode::InvalidArgument)]
+    pub lp_token_mint: Account<'info, token::Mint>,
+    #[account(mut, associated_token::mint = lp_token_mint, associated_token::authority = payer)]
+    pub lp_token_account: Account<'info, token::TokenAccount>,
     #[account(mut,
         token::mint = collateral_token_mint,
-        token::authority = vault,
-        seeds = [b"vault_wallet".as_ref(), payer.key.as_ref()],bump
+        token::authority = pool_wallet,
+        seeds = [b"pool_wallet".as_ref(), collateral_token_mint.key().as_ref()],bump
     )]
-    pub vault_wallet: Account<'info, token::TokenAccount>
+    pub pool_wallet: Account<'info, token::TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct CollectFees<'info> {
+    pub admin: Signer<'info>,
+    #[account(seeds = [b"config".as_ref()], bump = config.bump, has_one = admin @ ErrorCode::Unauthorized)]
+    pub config: Account<'info, AdminConfig>,
+    pub token_program: Program<'info, token::Token>,
+    pub collateral_token_mint: Account<'info, token::Mint>,
+    #[account(mut, seeds = [b"pool".as_ref(), collateral_token_mint.key().as_ref()], bump = pool.bump)]
+    pub pool: Account<'info, Pool>,
+    #[account(mut,
+        token::mint = collateral_token_mint,
+        token::authority = fee_vault,
+        seeds = [b"fee_vault".as_ref(), collateral_token_mint.key().as_ref()],bump
+    )]
+    pub fee_vault: Account<'info, token::TokenAccount>,
+    #[account(mut, token::mint = collateral_token_mint)]
+    pub treasury_token_account: Account<'info, token::TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct SetMockOracle<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(init_if_needed, payer = authority, space = 8 + size_of::<MockOracle>())]
+    pub mock_oracle: Account<'info, MockOracle>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
 #[instruction(thread_id: Vec<u8>)]
 pub struct Trigger<'info> {
+    /// CHECK: the config PDA, loaded by `AdminConfig::load`
+    #[account(seeds = [b"config".as_ref()], bump)]
+    pub config: UncheckedAccount<'info>,
     #[account(mut)]
     pub payer: Signer<'info>,
     pub system_program: Program<'info, System>,
@@ -658,47 +1837,435 @@ pub struct Trigger<'info> {
     pub associated_token_program: Program<'info, associated_token::AssociatedToken>,
     #[account(mut)]
     pub collateral_token_mint: Account<'info, token::Mint>,
-    pub mint_authority: SystemAccount<'info>,
+    #[account(seeds = [b"pool".as_ref(), collateral_token_mint.key().as_ref()], bump = pool.bump)]
+    pub pool: Box<Account<'info, Pool>>,
     #[account(mut, associated_token::mint = collateral_token_mint, associated_token::authority = payer)]
     pub collateral_token_account: Account<'info, token::TokenAccount>,
-    #[account(mut, seeds = [b"vault".as_ref(), payer.key.as_ref()], bump)]
-    pub vault: Account<'info, Vault>,
+    #[account(mut,
+        seeds = [b"vault".as_ref(), payer.key.as_ref(), market.key().as_ref(), vault.position_index.to_le_bytes().as_ref()],
+        bump = vault.bump
+    )]
+    pub vault: Box<Account<'info, Vault>>,
+    #[account(address = market.position_mint(&vault.position) @ ErrorCode::InvalidArgument)]
+    pub position_token_mint: Account<'info, token::Mint>,
+    #[account(associated_token::mint = position_token_mint, associated_token::authority = payer)]
+    pub position_token_account: Account<'info, token::TokenAccount>,
     #[account(mut,
         token::mint = collateral_token_mint,
         token::authority = vault,
-        seeds = [b"vault_wallet".as_ref(), payer.key.as_ref()],bump
+        seeds = [b"vault_wallet".as_ref(), vault.key().as_ref()],bump
     )]
     pub vault_wallet: Account<'info, token::TokenAccount>,
-    #[account(mut)]
-    pub short_token_mint: Account<'info, token::Mint>,
-    #[account(mut, associated_token::mint = short_token_mint, associated_token::authority = payer)]
-    pub short_token_account: Account<'info, token::TokenAccount>,
-    #[account(mut)]
-    pub long_token_mint: Account<'info, token::Mint>,
-    #[account(mut, associated_token::mint = long_token_mint, associated_token::authority = payer)]
-    pub long_token_account: Account<'info, token::TokenAccount>,
+    #[account(mut, seeds = [b"pool_wallet".as_ref(), collateral_token_mint.key().as_ref()], bump)]
+    pub pool_wallet: Account<'info, token::TokenAccount>,
     #[account(mut, address = Thread::pubkey(thread_authority.key(), thread_id))]
     pub thread: SystemAccount<'info>,
     #[account(seeds = [b"authority".as_ref(), payer.key.as_ref()], bump)]
     pub thread_authority: SystemAccount<'info>,
     #[account(address = clockwork_sdk::ID)]
     pub clockwork_program: Program<'info, clockwork_sdk::ThreadProgram>,
-    pub config: Account<'info, AdminConfig>,
-    #[account(address = config.loan_price_feed_id @ ErrorCode::InvalidArgument)]
-    pub pyth_loan_account: Account<'info, PriceFeed>,
+    pub market: Box<Account<'info, Market>>,
+    /// CHECK: the market's oracle, only forwarded to the liquidation instruction
+    #[account(address = market.price_feed @ ErrorCode::InvalidArgument)]
+    pub oracle_account: UncheckedAccount<'info>,
+}
+
+
+/// Operations that can be paused independently, e.g. pausing opens and
+/// deposits freezes new risk while users can still exit.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct PauseFlags {
+    pub opens: bool,
+    pub closes: bool,
+    pub deposits: bool,
+    pub withdrawals: bool,
+    pub liquidations: bool,
+}
+
+#[derive(Clone, Copy)]
+pub enum Operation {
+    Open,
+    Close,
+    Deposit,
+    Withdraw,
+    Liquidate,
+}
+
+impl PauseFlags {
+    pub fn check(&self, operation: Operation) -> Result<()> {
+        let paused = match operation {
+            Operation::Open => self.opens,
+            Operation::Close => self.closes,
+            Operation::Deposit => self.deposits,
+            Operation::Withdraw => self.withdrawals,
+            Operation::Liquidate => self.liquidations,
+        };
+        if paused {
+            return Err(error!(ErrorCode::Paused));
+        }
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct MarketParams {
+    pub max_leverage: u8,
+    pub maintenance_margin_bps: u16,
+    pub liquidation_fee_bps: u16,
+    /// Oldest oracle price, in seconds, accepted when opening or increasing
+    /// a position.
+    pub max_open_age: u64,
+    /// Oldest oracle price, in seconds, accepted when closing or liquidating.
+    /// At least `max_open_age` so positions can still be closed during short
+    /// oracle outages.
+    pub max_close_age: u64,
+    /// Price used to open or increase positions, unless spot is worse for
+    /// the trader.
+    pub entry_price_mode: PriceMode,
+    /// Price used to check the maintenance margin on liquidation.
+    pub liquidation_price_mode: PriceMode,
+    pub circuit_breaker: CircuitBreakerParams,
+    /// Minimum seconds between two TWAP observations. The longest TWAP
+    /// window is `twap::TWAP_OBSERVATIONS` times this.
+    pub twap_interval: u64,
+    /// Widest accepted oracle confidence interval, in bps of the price.
+    pub max_conf_bps: u16,
+    pub schedule: TradingSchedule,
+    /// Fill entries and exits at the edge of the confidence interval that is
+    /// worse for the trader.
+    pub conservative_pricing: bool,
+    /// Fee charged on the notional opened, taken from the collateral.
+    pub open_fee_bps: u16,
+    /// Fee charged on the notional closed, taken from the payout.
+    pub close_fee_bps: u16,
+    /// Funding rate per day, in bps of the notional, when all open interest
+    /// is on one side. Scales down linearly with the imbalance.
+    pub max_funding_rate_bps: u16,
+}
+
+impl MarketParams {
+    pub fn validate(&self, config: &AdminConfig) -> Result<()> {
+        if self.max_leverage == 0 {
+            return Err(error!(ErrorCode::InvalidArgument));
+        }
+        if self.max_leverage > config.params.max_leverage {
+            return Err(error!(ErrorCode::LeverageTooHigh));
+        }
+        // A position at max leverage has to open above the maintenance
+        // margin, with room left for the open fee and the spread.
+        if self.maintenance_margin_bps as i128 * self.max_leverage as i128 >= math::BPS_DENOMINATOR
+            || self.liquidation_fee_bps > self.maintenance_margin_bps
+            || self.max_conf_bps == 0
+            || self.open_fee_bps as i128 > math::BPS_DENOMINATOR
+            || self.close_fee_bps as i128 > math::BPS_DENOMINATOR
+            || self.max_funding_rate_bps as i128 > math::BPS_DENOMINATOR
+            || self.max_open_age == 0
+            || self.twap_interval == 0
+            || self.max_close_age < self.max_open_age
+            || self.schedule.open_minute >= self.schedule.close_minute
+            || self.schedule.close_minute > MINUTES_PER_DAY {
+            return Err(error!(ErrorCode::InvalidArgument));
+        }
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct CircuitBreakerParams {
+    /// Largest accepted move from the last accepted price, in bps. Zero
+    /// disables the circuit breaker.
+    pub max_deviation_bps: u16,
+    /// Seconds a last accepted price stays the reference for new prices.
+    pub window: u64,
+    /// Seconds a tripped circuit breaker halts trading unless an admin
+    /// resets it earlier.
+    pub cooldown: u64,
+    /// Also halt closes, not only opens, while tripped.
+    pub halt_closes: bool,
+}
+
+/// Outcome of checking a price against the circuit breaker.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BreakerUpdate {
+    /// The price can be the new reference.
+    Accepted,
+    /// The price moved far enough to trip the circuit breaker.
+    Tripped,
+    /// The circuit breaker tripped earlier and is still cooling down.
+    CoolingDown,
+}
+
+/// Circuit breaker state of a market.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct CircuitBreaker {
+    pub last_price: u64,
+    pub last_price_time: i64,
+    /// Time the circuit breaker tripped, zero while it isn't tripped.
+    pub tripped_at: i64,
+}
+
+impl CircuitBreaker {
+    /// Makes `price` the reference and clears an expired trip.
+    fn accept(&mut self, price: u64, now: i64) {
+        *self = CircuitBreaker {
+            last_price: price,
+            last_price_time: now,
+            tripped_at: 0,
+        };
+    }
+}
+
+#[event]
+pub struct CircuitBreakerTrip {
+    pub market: Pubkey,
+    pub last_price: u64,
+    pub price: u64,
+    pub timestamp: i64,
+}
+
+const SECONDS_PER_DAY: i64 = 86_400;
+const MINUTES_PER_DAY: u16 = 1_440;
+/// LP shares locked by the first deposit into a pool.
+const MINIMUM_LIQUIDITY: u64 = 1_000;
+
+/// Weekly trading session of a market in UTC. Outside of it the market is
+/// closed: positions can't be opened or liquidated, and can only be closed
+/// at the last oracle price if `allow_closed_exits` is set.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct TradingSchedule {
+    /// Bit `n` is set if the market trades on weekday `n`, Monday being 0.
+    pub days: u8,
+    /// Minutes after midnight UTC the session opens.
+    pub open_minute: u16,
+    /// Minutes after midnight UTC the session closes.
+    pub close_minute: u16,
+    pub allow_closed_exits: bool,
+}
+
+impl TradingSchedule {
+    pub fn is_open(&self, unix_timestamp: i64) -> bool {
+        // 1970-01-01 was a Thursday.
+        let weekday = (unix_timestamp.div_euclid(SECONDS_PER_DAY) + 3).rem_euclid(7);
+        let minute = (unix_timestamp.rem_euclid(SECONDS_PER_DAY) / 60) as u16;
+        self.days & (1 << weekday) != 0
+            && minute >= self.open_minute
+            && minute < self.close_minute
+    }
+}
+
+#[account]
+pub struct Market {
+    pub symbol: [u8; 16],
+    pub price_feed: Pubkey,
+    pub oracle: OracleKind,
+    /// Oracle read when `price_feed` is stale.
+    pub fallback_feed: Option<Pubkey>,
+    pub fallback_oracle: Option<OracleKind>,
+    pub collateral_mint: Pubkey,
+    pub long_mint: Pubkey,
+    pub short_mint: Pubkey,
+    pub params: MarketParams,
+    pub circuit_breaker: CircuitBreaker,
+    pub twap: TwapAccumulator,
+    pub funding: FundingState,
+    pub paused: PauseFlags,
+    pub enabled: bool,
+    pub bump: u8,
+}
+
+impl Market {
+    /// Fails if `operation` is paused globally or in this market.
+    pub fn check_paused(&self, config: &AdminConfig, operation: Operation) -> Result<()> {
+        config.paused.check(operation)?;
+        self.paused.check(operation)
+    }
+
+    /// Compares `price` with the last accepted price without recording it:
+    /// `Tripped` if it moved more than `max_deviation_bps` within the window.
+    pub fn check_circuit_breaker(&self, price: u64, now: i64) -> Result<BreakerUpdate> {
+        let params = &self.params.circuit_breaker;
+        let breaker = &self.circuit_breaker;
+        if breaker.tripped_at != 0 {
+            if now < breaker.tripped_at.saturating_add(params.cooldown as i64) {
+                return Ok(BreakerUpdate::CoolingDown);
+            }
+            // The cooldown is over, so the next price starts a new reference.
+            return Ok(BreakerUpdate::Accepted);
+        }
+        let in_window = now.saturating_sub(breaker.last_price_time) <= params.window as i64;
+        if params.max_deviation_bps != 0 && breaker.last_price != 0 && in_window
+            && math::deviation_bps(breaker.last_price, price)? > params.max_deviation_bps as u64 {
+            return Ok(BreakerUpdate::Tripped);
+        }
+        Ok(BreakerUpdate::Accepted)
+    }
+
+    /// Runs `check_circuit_breaker` and records the outcome: trips the
+    /// circuit breaker and emits `CircuitBreakerTrip`, or makes `price` the
+    /// new reference.
+    pub fn update_circuit_breaker(&mut self, key: Pubkey, price: u64) -> Result<BreakerUpdate> {
+        let now = Clock::get()?.unix_timestamp;
+        let update = self.check_circuit_breaker(price, now)?;
+        let breaker = &mut self.circuit_breaker;
+        match update {
+            BreakerUpdate::Accepted => breaker.accept(price, now),
+            BreakerUpdate::Tripped => {
+                breaker.tripped_at = now;
+                emit!(CircuitBreakerTrip {
+                    market: key,
+                    last_price: breaker.last_price,
+                    price,
+                    timestamp: now,
+                });
+            }
+            BreakerUpdate::CoolingDown => {}
+        }
+        Ok(update)
+    }
+
+    /// Folds the spot mid price an instruction read into the TWAP and
+    /// checks it against the circuit breaker, which halts opens, and closes
+    /// if configured. A price the circuit breaker would trip on fails with
+    /// `CircuitBreakerTripped` and leaves the reference unchanged, so only
+    /// `update_price` persists trips. Liquidations skip the circuit breaker.
+    pub fn observe_price(&mut self, price: u64, action: PriceAction) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        if action != PriceAction::Liquidate {
+            let halts = action == PriceAction::Open || self.params.circuit_breaker.halt_closes;
+            match self.check_circuit_breaker(price, now)? {
+                BreakerUpdate::Accepted => self.circuit_breaker.accept(price, now),
+                _ if halts => return Err(error!(ErrorCode::CircuitBreakerTripped)),
+                _ => {}
+            }
+        }
+        self.twap.update(price, now, self.params.twap_interval)
+    }
+
+    /// Accrues funding up to now.
+    pub fn accrue_funding(&mut self) -> Result<()> {
+        let max_rate_bps = self.params.max_funding_rate_bps;
+        self.funding.accrue(max_rate_bps, Clock::get()?.unix_timestamp)
+    }
+
+    pub fn position_mint(&self, position: &Position) -> Pubkey {
+        match position {
+            Position::Long => self.long_mint,
+            Position::Short => self.short_mint,
+        }
+    }
 }
 
+/// Liquidity pool for one collateral mint. It is the counterparty of every
+/// position; its NAV is the balance of the pool wallet and LP shares are
+/// minted and burned pro rata to it.
+#[account]
+pub struct Pool {
+    pub collateral_mint: Pubkey,
+    pub lp_mint: Pubkey,
+    /// Insurance share of the fees held in the fee vault.
+    pub insurance_fees: u64,
+    /// Treasury share of the fees held in the fee vault, swept by
+    /// `collect_fees`.
+    pub treasury_fees: u64,
+    /// Open notional of the positions the pool backs. Opens can't take it
+    /// above the pool wallet balance and withdrawals can't take the balance
+    /// below it. At that moment the wallet covers the largest profit every
+    /// short can make, but long profits are unbounded and only covered up to
+    /// a 100% price move.
+    pub reserved: u64,
+    pub bump: u8,
+}
+
+impl Pool {
+    /// Books the insurance and treasury shares of a trading fee and returns
+    /// their sum, which belongs in the fee vault. The rest is the LP share.
+    pub fn book_fee(&mut self, split: &FeeSplit, fee: u64) -> Result<u64> {
+        let insurance = math::apply_bps(fee, split.insurance_bps)?;
+        let treasury = math::apply_bps(fee, split.treasury_bps)?;
+        self.insurance_fees = self.insurance_fees
+            .checked_add(insurance)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        self.treasury_fees = self.treasury_fees
+            .checked_add(treasury)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        Ok(insurance + treasury)
+    }
+
+    /// Reserves `notional` for a new position, failing if the pool wallet
+    /// holding `liquidity` can't back it.
+    pub fn reserve(&mut self, notional: u64, liquidity: u64) -> Result<()> {
+        let reserved = self.reserved
+            .checked_add(notional)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        if reserved > liquidity {
+            return Err(error!(ErrorCode::InsufficientLiquidity));
+        }
+        self.reserved = reserved;
+        Ok(())
+    }
+
+    pub fn release(&mut self, notional: u64) -> Result<()> {
+        self.reserved = self.reserved
+            .checked_sub(notional)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        Ok(())
+    }
+}
 
 #[account]
 pub struct Vault {
+    pub owner: Pubkey,
+    pub market: Pubkey,
+    pub position_index: u64,
+    pub bump: u8,
     pub amount: u64,
-    pub price_entered: i64,
+    /// Entry price with `math::PRICE_DECIMALS` decimals.
+    pub price_entered: u64,
     pub position: Position,
+    /// Notional exposure in collateral token units (`amount * leverage`).
     pub collateral_locked: u64,
+    /// Effective leverage (`collateral_locked / amount`), rounded down and
+    /// capped at `u8::MAX`.
+    pub leverage: u8,
+    /// Market funding index at the last funding settlement.
+    pub funding_index: i128,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
 pub enum Position {
     Long,
     Short,
+}
+
+impl Position {
+    /// Confidence bound a position of this direction is opened at.
+    pub fn entry_bound(&self) -> PriceBound {
+        match self {
+            Position::Long => PriceBound::Upper,
+            Position::Short => PriceBound::Lower,
+        }
+    }
+
+    /// Rejects an `action` executing at `price`, with `math::PRICE_DECIMALS`
+    /// decimals, if that is worse for the trader than `acceptable_price`.
+    pub fn check_acceptable_price(&self, action: PriceAction, price: u64, acceptable_price: Option<u64>) -> Result<()> {
+        let acceptable_price = match acceptable_price {
+            Some(acceptable_price) => acceptable_price,
+            None => return Ok(()),
+        };
+        let buying = (action == PriceAction::Open) == (*self == Position::Long);
+        if (buying && price > acceptable_price) || (!buying && price < acceptable_price) {
+            msg!("Execution price {} is worse than the acceptable price {}", price, acceptable_price);
+            return Err(error!(ErrorCode::SlippageExceeded));
+        }
+        Ok(())
+    }
+
+    /// Confidence bound a position of this direction is closed at.
+    pub fn exit_bound(&self) -> PriceBound {
+        match self {
+            Position::Long => PriceBound::Lower,
+            Position::Short => PriceBound::Upper,
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,179 @@
+use anchor_lang::prelude::*;
+use pyth_sdk::Price;
+
+use crate::ErrorCode;
+use crate::Position;
+
+/// Number of decimals every price and collateral value is normalised to
+/// before they are combined.
+pub const PRICE_DECIMALS: u32 = 9;
+
+pub const BPS_DENOMINATOR: i128 = 10_000;
+
+fn pow10(exp: u32) -> Result<u128> {
+    10u128.checked_pow(exp).ok_or(error!(ErrorCode::Overflow))
+}
+
+/// Converts a Pyth `price * 10^expo` into a positive price with
+/// `PRICE_DECIMALS` decimals.
+pub fn normalize_price(price: &Price) -> Result<u64> {
+    if price.price <= 0 {
+        return Err(error!(ErrorCode::PythError));
+    }
+    let raw = price.price as u128;
+    let shift = PRICE_DECIMALS as i32 + price.expo;
+    let scaled = if shift >= 0 {
+        raw.checked_mul(pow10(shift as u32)?)
+            .ok_or(error!(ErrorCode::Overflow))?
+    } else {
+        raw / pow10(shift.unsigned_abs())?
+    };
+    u64::try_from(scaled).map_err(|_| error!(ErrorCode::Overflow))
+}
+
+/// Width of the confidence interval of a Pyth price in basis points of the
+/// price.
+pub fn confidence_bps(price: &Price) -> Result<u64> {
+    if price.price <= 0 {
+        return Err(error!(ErrorCode::PythError));
+    }
+    let bps = (price.conf as u128)
+        .checked_mul(BPS_DENOMINATOR as u128)
+        .ok_or(error!(ErrorCode::Overflow))?
+        / price.price as u128;
+    u64::try_from(bps).map_err(|_| error!(ErrorCode::Overflow))
+}
+
+/// Distance of `price` from `reference` in basis points of `reference`.
+pub fn deviation_bps(reference: u64, price: u64) -> Result<u64> {
+    if reference == 0 {
+        return Err(error!(ErrorCode::InvalidArgument));
+    }
+    let bps = (reference.abs_diff(price) as u128)
+        .checked_mul(BPS_DENOMINATOR as u128)
+        .ok_or(error!(ErrorCode::Overflow))?
+        / reference as u128;
+    Ok(u64::try_from(bps).unwrap_or(u64::MAX))
+}
+
+/// Converts an SPL token amount with `decimals` decimals into the common scale.
+pub fn normalize_amount(amount: u64, decimals: u8) -> Result<u128> {
+    let shift = PRICE_DECIMALS as i32 - decimals as i32;
+    if shift >= 0 {
+        (amount as u128)
+            .checked_mul(pow10(shift as u32)?)
+            .ok_or(error!(ErrorCode::Overflow))
+    } else {
+        Ok(amount as u128 / pow10(shift.unsigned_abs())?)
+    }
+}
+
+/// Converts a value in the common scale back into an SPL token amount,
+/// rounding down.
+pub fn denormalize_amount(value: u128, decimals: u8) -> Result<u64> {
+    let shift = PRICE_DECIMALS as i32 - decimals as i32;
+    let amount = if shift >= 0 {
+        value / pow10(shift as u32)?
+    } else {
+        value
+            .checked_mul(pow10(shift.unsigned_abs())?)
+            .ok_or(error!(ErrorCode::Overflow))?
+    };
+    u64::try_from(amount).map_err(|_| error!(ErrorCode::Overflow))
+}
+
+/// Value of `units` of the synthetic asset (in the common scale) at `price`.
+pub fn notional(units: u128, price: u64) -> Result<u128> {
+    let value = units
+        .checked_mul(price as u128)
+        .ok_or(error!(ErrorCode::Overflow))?;
+    Ok(value / pow10(PRICE_DECIMALS)?)
+}
+
+/// Units of the synthetic asset that `value` buys at `price`.
+pub fn position_units(value: u128, price: u64) -> Result<u128> {
+    if price == 0 {
+        return Err(error!(ErrorCode::InvalidArgument));
+    }
+    let scaled = value
+        .checked_mul(pow10(PRICE_DECIMALS)?)
+        .ok_or(error!(ErrorCode::Overflow))?;
+    Ok(scaled / price as u128)
+}
+
+/// Size-weighted average entry price when a position with an entry notional
+/// of `value` at `entry` is increased by `added_value` at `price`.
+pub fn average_entry_price(value: u128, entry: u64, added_value: u128, price: u64) -> Result<u64> {
+    let units = position_units(value, entry)?
+        .checked_add(position_units(added_value, price)?)
+        .ok_or(error!(ErrorCode::Overflow))?;
+    if units == 0 {
+        return Ok(price);
+    }
+    let total = value
+        .checked_add(added_value)
+        .ok_or(error!(ErrorCode::Overflow))?
+        .checked_mul(pow10(PRICE_DECIMALS)?)
+        .ok_or(error!(ErrorCode::Overflow))?;
+    u64::try_from(total / units).map_err(|_| error!(ErrorCode::Overflow))
+}
+
+/// Profit (positive) or loss (negative) of a position with an entry notional
+/// of `value` when the price moves from `entry` to `exit`.
+pub fn pnl(value: u128, entry: u64, exit: u64, position: &Position) -> Result<i128> {
+    let units = position_units(value, entry)?;
+    let exit_value = notional(units, exit)?;
+    let value = i128::try_from(value).map_err(|_| error!(ErrorCode::Overflow))?;
+    let exit_value = i128::try_from(exit_value).map_err(|_| error!(ErrorCode::Overflow))?;
+    let delta = match position {
+        Position::Long => exit_value.checked_sub(value),
+        Position::Short => value.checked_sub(exit_value),
+    };
+    delta.ok_or(error!(ErrorCode::Overflow))
+}
+
+/// Return of a position in basis points of its entry notional.
+pub fn return_bps(entry: u64, exit: u64, position: &Position) -> Result<i64> {
+    if entry == 0 {
+        return Err(error!(ErrorCode::InvalidArgument));
+    }
+    let delta = match position {
+        Position::Long => exit as i128 - entry as i128,
+        Position::Short => entry as i128 - exit as i128,
+    };
+    let bps = delta
+        .checked_mul(BPS_DENOMINATOR)
+        .ok_or(error!(ErrorCode::Overflow))?
+        / entry as i128;
+    i64::try_from(bps).map_err(|_| error!(ErrorCode::Overflow))
+}
+
+/// Applies `pnl` (in the common scale) to a collateral amount of a mint with
+/// `decimals` decimals, floored at zero.
+pub fn apply_pnl(collateral: u64, decimals: u8, pnl: i128) -> Result<u64> {
+    let value = i128::try_from(normalize_amount(collateral, decimals)?)
+        .map_err(|_| error!(ErrorCode::Overflow))?;
+    let remaining = value.checked_add(pnl).ok_or(error!(ErrorCode::Overflow))?;
+    denormalize_amount(remaining.max(0) as u128, decimals)
+}
+
+/// `bps` basis points of `amount`, rounded down.
+pub fn apply_bps(amount: u64, bps: u16) -> Result<u64> {
+    let value = (amount as u128)
+        .checked_mul(bps as u128)
+        .ok_or(error!(ErrorCode::Overflow))?
+        / BPS_DENOMINATOR as u128;
+    u64::try_from(value).map_err(|_| error!(ErrorCode::Overflow))
+}
+
+/// `amount * numerator / denominator`, rounded down.
+pub fn pro_rata(amount: u64, numerator: u64, denominator: u64) -> Result<u64> {
+    if denominator == 0 {
+        return Err(error!(ErrorCode::InvalidArgument));
+    }
+    let value = (amount as u128)
+        .checked_mul(numerator as u128)
+        .ok_or(error!(ErrorCode::Overflow))?
+        / denominator as u128;
+    u64::try_from(value).map_err(|_| error!(ErrorCode::Overflow))
+}
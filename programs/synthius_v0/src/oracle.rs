@@ -0,0 +1,415 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::hash::hash;
+use anchor_lang::solana_program::pubkey;
+use pyth_sdk::Price;
+use pyth_sdk_solana::state::PriceStatus;
+
+use crate::math;
+use crate::pyth::{PriceFeed, PYTH_PROGRAM_ID};
+use crate::ErrorCode;
+use crate::Market;
+use crate::MarketParams;
+use crate::Position;
+
+#[cfg(all(feature = "mock-oracle", feature = "mainnet"))]
+compile_error!("The `mock-oracle` feature must not be enabled for mainnet builds.");
+
+/// Switchboard v2 oracle program, deployed at the same address on every cluster.
+pub const SWITCHBOARD_PROGRAM_ID: Pubkey = pubkey!("SW1TCH7qEPTdLsDHRgPuMQjbQxKdH2aBStViMFnt64f");
+
+/// Price provider behind a market's oracle account.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OracleKind {
+    Pyth,
+    Switchboard,
+    /// `MockOracle` account of this program, only accepted by builds with the
+    /// `mock-oracle` feature.
+    Mock,
+}
+
+/// Trading status reported by an oracle.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OracleStatus {
+    Trading,
+    Halted,
+    Unknown,
+}
+
+/// Which price a market reads for entries or liquidation checks. Providers
+/// without an EMA read the spot price for every mode.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum PriceMode {
+    /// Latest aggregate price.
+    Spot,
+    /// Exponentially-weighted moving average price.
+    Ema,
+    /// Midpoint of the spot and EMA prices.
+    Blend,
+}
+
+/// What a price is read for.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PriceAction {
+    Open,
+    Close,
+    Liquidate,
+}
+
+/// Price read by `MarketOracle::price`.
+pub struct Quote {
+    /// Price the action executes at.
+    pub price: u64,
+    /// Middle of the confidence interval.
+    pub mid: u64,
+    /// Spot mid price, which the circuit breaker and the TWAP track
+    /// whatever mode `price` was read in.
+    pub spot: u64,
+}
+
+/// Side of the confidence interval a price is read from.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PriceBound {
+    Mid,
+    Lower,
+    Upper,
+}
+
+/// Latest price published by an oracle account, as `price * 10^expo`.
+pub trait Oracle {
+    fn price(&self) -> i64;
+    fn conf(&self) -> u64;
+    fn expo(&self) -> i32;
+    fn publish_time(&self) -> i64;
+    fn status(&self) -> OracleStatus;
+
+    /// Exponentially-weighted moving average price, if the provider has one.
+    fn ema_price(&self) -> Option<Price> {
+        None
+    }
+
+    fn spot_price(&self) -> Price {
+        Price {
+            price: self.price(),
+            conf: self.conf(),
+            expo: self.expo(),
+            publish_time: self.publish_time(),
+        }
+    }
+}
+
+impl Oracle for PriceFeed {
+    fn price(&self) -> i64 {
+        self.get_price_unchecked().price
+    }
+
+    fn conf(&self) -> u64 {
+        self.get_price_unchecked().conf
+    }
+
+    fn expo(&self) -> i32 {
+        self.get_price_unchecked().expo
+    }
+
+    fn publish_time(&self) -> i64 {
+        self.get_price_unchecked().publish_time
+    }
+
+    fn status(&self) -> OracleStatus {
+        match self.price_status() {
+            PriceStatus::Trading => OracleStatus::Trading,
+            PriceStatus::Halted => OracleStatus::Halted,
+            _ => OracleStatus::Unknown,
+        }
+    }
+
+    fn ema_price(&self) -> Option<Price> {
+        Some(self.get_ema_price_unchecked())
+    }
+}
+
+/// Latest confirmed round of a Switchboard v2 aggregator account, rescaled
+/// to `math::PRICE_DECIMALS`.
+pub struct SwitchboardFeed {
+    price: i64,
+    conf: u64,
+    publish_time: i64,
+}
+
+// Byte offsets, discriminator included, of `latest_confirmed_round.round_open_timestamp`,
+// `.result` and `.std_deviation` in the packed `AggregatorAccountData` account.
+const SWITCHBOARD_ROUND_OPEN_TIMESTAMP: usize = 358;
+const SWITCHBOARD_RESULT: usize = 366;
+const SWITCHBOARD_STD_DEVIATION: usize = 386;
+const SWITCHBOARD_DECIMAL_LEN: usize = 20;
+
+impl SwitchboardFeed {
+    pub fn load(data: &[u8]) -> Result<Self> {
+        let discriminator = &hash(b"account:AggregatorAccountData").to_bytes()[..8];
+        if data.len() < SWITCHBOARD_STD_DEVIATION + SWITCHBOARD_DECIMAL_LEN
+            || &data[..8] != discriminator {
+            return Err(error!(ErrorCode::InvalidOracle));
+        }
+        let timestamp = &data[SWITCHBOARD_ROUND_OPEN_TIMESTAMP..SWITCHBOARD_RESULT];
+        let price = switchboard_decimal(&data[SWITCHBOARD_RESULT..])?;
+        let conf = switchboard_decimal(&data[SWITCHBOARD_STD_DEVIATION..])?;
+        Ok(SwitchboardFeed {
+            price: i64::try_from(price).map_err(|_| error!(ErrorCode::Overflow))?,
+            conf: u64::try_from(conf.unsigned_abs()).map_err(|_| error!(ErrorCode::Overflow))?,
+            publish_time: i64::from_le_bytes(timestamp.try_into().unwrap()),
+        })
+    }
+}
+
+/// Reads a `SwitchboardDecimal { mantissa: i128, scale: u32 }` as a value with
+/// `math::PRICE_DECIMALS` decimals.
+fn switchboard_decimal(data: &[u8]) -> Result<i128> {
+    let mantissa = i128::from_le_bytes(data[..16].try_into().unwrap());
+    let scale = u32::from_le_bytes(data[16..20].try_into().unwrap());
+    if scale > math::PRICE_DECIMALS {
+        let divisor = 10i128
+            .checked_pow(scale - math::PRICE_DECIMALS)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        Ok(mantissa / divisor)
+    } else {
+        mantissa
+            .checked_mul(10i128.pow(math::PRICE_DECIMALS - scale))
+            .ok_or(error!(ErrorCode::Overflow))
+    }
+}
+
+impl Oracle for SwitchboardFeed {
+    fn price(&self) -> i64 {
+        self.price
+    }
+
+    fn conf(&self) -> u64 {
+        self.conf
+    }
+
+    fn expo(&self) -> i32 {
+        -(math::PRICE_DECIMALS as i32)
+    }
+
+    fn publish_time(&self) -> i64 {
+        self.publish_time
+    }
+
+    fn status(&self) -> OracleStatus {
+        OracleStatus::Trading
+    }
+}
+
+/// Price written into a `MockOracle` by `set_mock_oracle`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct MockPrice {
+    pub price: i64,
+    pub conf: u64,
+    pub expo: i32,
+    pub ema_price: i64,
+    pub ema_conf: u64,
+    /// Defaults to the current cluster time when `None` is written.
+    pub publish_time: Option<i64>,
+    pub status: OracleStatus,
+}
+
+/// Price account with arbitrary values for local tests.
+#[account]
+pub struct MockOracle {
+    pub authority: Pubkey,
+    pub price: MockPrice,
+}
+
+impl Oracle for MockOracle {
+    fn price(&self) -> i64 {
+        self.price.price
+    }
+
+    fn conf(&self) -> u64 {
+        self.price.conf
+    }
+
+    fn expo(&self) -> i32 {
+        self.price.expo
+    }
+
+    fn publish_time(&self) -> i64 {
+        self.price.publish_time.unwrap_or_default()
+    }
+
+    fn status(&self) -> OracleStatus {
+        self.price.status
+    }
+
+    fn ema_price(&self) -> Option<Price> {
+        Some(Price {
+            price: self.price.ema_price,
+            conf: self.price.ema_conf,
+            expo: self.price.expo,
+            publish_time: self.publish_time(),
+        })
+    }
+}
+
+/// Parses `account` as a price account of `kind`.
+pub fn load(kind: OracleKind, account: &AccountInfo) -> Result<Box<dyn Oracle>> {
+    let data = account.try_borrow_data()?;
+    match kind {
+        OracleKind::Pyth => {
+            if account.owner != &PYTH_PROGRAM_ID {
+                return Err(error!(ErrorCode::InvalidOracle));
+            }
+            Ok(Box::new(PriceFeed::try_deserialize(&mut &data[..])?))
+        }
+        OracleKind::Switchboard => {
+            if account.owner != &SWITCHBOARD_PROGRAM_ID {
+                return Err(error!(ErrorCode::InvalidOracle));
+            }
+            Ok(Box::new(SwitchboardFeed::load(&data)?))
+        }
+        OracleKind::Mock => {
+            if !cfg!(feature = "mock-oracle") || account.owner != &crate::ID {
+                return Err(error!(ErrorCode::InvalidOracle));
+            }
+            Ok(Box::new(MockOracle::try_deserialize(&mut &data[..])?))
+        }
+    }
+}
+
+/// Oracles a market reads its price from.
+pub struct MarketOracle {
+    primary: Box<dyn Oracle>,
+    fallback: Option<Box<dyn Oracle>>,
+}
+
+impl MarketOracle {
+    /// Loads the market's primary oracle and, if the caller passed it, the
+    /// fallback oracle.
+    pub fn load(market: &Market, primary: &AccountInfo, fallback: Option<&AccountInfo>) -> Result<Self> {
+        let fallback = match (market.fallback_oracle, fallback) {
+            (Some(kind), Some(account)) => Some(load(kind, account)?),
+            _ => None,
+        };
+        Ok(MarketOracle {
+            primary: load(market.oracle, primary)?,
+            fallback,
+        })
+    }
+
+    /// Reads the price `action` on `position` executes at in `market`.
+    /// Opens and liquidations are rejected while the market is closed by its
+    /// schedule or a halted oracle; closes then settle at the last oracle
+    /// price if the schedule allows it.
+    pub fn price(&self, market: &Market, action: PriceAction, position: &Position) -> Result<Quote> {
+        let params = &market.params;
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        let open = params.schedule.is_open(current_timestamp)
+            && self.primary.status() != OracleStatus::Halted;
+        if !open && (action != PriceAction::Close || !params.schedule.allow_closed_exits) {
+            return Err(error!(ErrorCode::MarketClosed));
+        }
+        match action {
+            PriceAction::Open => {
+                let bound = position.entry_bound();
+                let quote = self.read(params, params.entry_price_mode, params.max_open_age, bound)?;
+                if params.entry_price_mode == PriceMode::Spot {
+                    return Ok(quote);
+                }
+                // Exits read spot, so an entry at a lagging EMA could be
+                // closed right away at a profit. Entries take the worse price.
+                let spot = self.read(params, PriceMode::Spot, params.max_open_age, bound)?;
+                Ok(match position {
+                    Position::Long if spot.price > quote.price => spot,
+                    Position::Short if spot.price < quote.price => spot,
+                    _ => quote,
+                })
+            }
+            PriceAction::Close => {
+                // The feed stops updating while the market is closed.
+                let max_age = if open { params.max_close_age } else { u64::MAX };
+                self.read(params, PriceMode::Spot, max_age, position.exit_bound())
+            }
+            PriceAction::Liquidate => {
+                self.read(params, params.liquidation_price_mode, params.max_close_age, PriceBound::Mid)
+            }
+        }
+    }
+
+    /// Mid spot price, regardless of the trading schedule.
+    pub fn spot_price(&self, params: &MarketParams) -> Result<u64> {
+        Ok(self.read(params, PriceMode::Spot, params.max_close_age, PriceBound::Mid)?.mid)
+    }
+
+    /// Reads the `mode` price for a market, rejecting prices older than
+    /// `max_age` seconds or with a confidence interval wider than
+    /// `max_conf_bps`, and normalises it to `math::PRICE_DECIMALS`. A stale
+    /// primary oracle is replaced by the fallback. With `conservative_pricing`
+    /// set, `bound` selects `price - conf` or `price + conf` instead.
+    fn read(
+        &self,
+        params: &MarketParams,
+        mode: PriceMode,
+        max_age: u64,
+        bound: PriceBound,
+    ) -> Result<Quote> {
+        let current_timestamp = Clock::get()?.unix_timestamp;
+        let mut price = match read_price(self.primary.as_ref(), mode, current_timestamp, max_age)? {
+            Some(price) => price,
+            None => {
+                let fallback = self.fallback.as_ref().ok_or(error!(ErrorCode::OracleOffline))?;
+                read_price(fallback.as_ref(), mode, current_timestamp, max_age)?
+                    .ok_or(error!(ErrorCode::OracleOffline))?
+            }
+        };
+        if math::confidence_bps(&price)? > params.max_conf_bps as u64 {
+            return Err(error!(ErrorCode::PriceConfidenceTooWide));
+        }
+        let mid = math::normalize_price(&price)?;
+        if params.conservative_pricing {
+            let conf = i64::try_from(price.conf).map_err(|_| error!(ErrorCode::Overflow))?;
+            price.price = match bound {
+                PriceBound::Mid => Some(price.price),
+                PriceBound::Lower => price.price.checked_sub(conf),
+                PriceBound::Upper => price.price.checked_add(conf),
+            }
+            .ok_or(error!(ErrorCode::Overflow))?;
+        }
+        let spot = match mode {
+            PriceMode::Spot => mid,
+            _ => self.spot_price(params)?,
+        };
+        Ok(Quote {
+            price: math::normalize_price(&price)?,
+            mid,
+            spot,
+        })
+    }
+}
+
+/// The `mode` price of `oracle`, or `None` if it is older than `max_age`
+/// seconds.
+fn read_price(oracle: &dyn Oracle, mode: PriceMode, current_timestamp: i64, max_age: u64) -> Result<Option<Price>> {
+    let fresh = |price: Price| (current_timestamp.abs_diff(price.publish_time) <= max_age).then_some(price);
+    let spot = oracle.spot_price();
+    let ema = match oracle.ema_price() {
+        Some(ema) => ema,
+        None => return Ok(fresh(spot)),
+    };
+    let (spot, ema) = match mode {
+        PriceMode::Spot => return Ok(fresh(spot)),
+        PriceMode::Ema => return Ok(fresh(ema)),
+        PriceMode::Blend => match (fresh(spot), fresh(ema)) {
+            (Some(spot), Some(ema)) => (spot, ema),
+            _ => return Ok(None),
+        },
+    };
+    if spot.expo != ema.expo {
+        return Err(error!(ErrorCode::PythError));
+    }
+    // Keep the wider of the two confidence intervals.
+    let mid = (spot.price as i128 + ema.price as i128) / 2;
+    Ok(Some(Price {
+        price: mid as i64,
+        conf: spot.conf.max(ema.conf),
+        ..spot
+    }))
+}
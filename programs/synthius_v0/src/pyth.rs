@@ -1,23 +1,86 @@
 use std::ops::Deref;
-use std::str::FromStr;
 use anchor_lang::prelude::*;
-use pyth_sdk_solana::state::load_price_account;
+use anchor_lang::solana_program::pubkey;
+use pyth_sdk_solana::state::{load_price_account, PriceStatus};
 
 use crate::ErrorCode;
+use crate::PauseFlags;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ConfigParams {
+    /// Upper bound for the `max_leverage` of every market.
+    pub max_leverage:             u8,
+    pub fee_split:                FeeSplit,
+}
+
+impl ConfigParams {
+    pub fn validate(&self) -> Result<()> {
+        let split = &self.fee_split;
+        if split.lp_bps as i128 + split.insurance_bps as i128 + split.treasury_bps as i128
+            != crate::math::BPS_DENOMINATOR {
+            return Err(error!(ErrorCode::InvalidArgument));
+        }
+        Ok(())
+    }
+}
+
+/// How trading fees are shared, in bps of the fee. The LP share stays in the
+/// pool wallet; the insurance and treasury shares go to the fee vault.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct FeeSplit {
+    pub lp_bps:                   u16,
+    pub insurance_bps:            u16,
+    pub treasury_bps:             u16,
+}
 
 #[account]
 pub struct AdminConfig {
-    pub loan_price_feed_id:       Pubkey,
+    /// Signs every configuration change.
+    pub admin:                    Pubkey,
+    /// Admin proposed by `propose_admin`, until it accepts.
+    pub pending_admin:            Option<Pubkey>,
+    pub params:                   ConfigParams,
+    /// Operations paused in every market and pool. Deposits and withdrawals
+    /// are only paused here since pools aren't tied to a market.
+    pub paused:                   PauseFlags,
+    pub bump:                     u8,
+}
+
+impl AdminConfig {
+    /// Loads the singleton config PDA, failing with `UnInitialize` until
+    /// `initialize` has run.
+    pub fn load(account: &AccountInfo) -> Result<AdminConfig> {
+        if account.owner != &crate::ID || account.data_is_empty() {
+            return Err(error!(ErrorCode::UnInitialize));
+        }
+        AdminConfig::try_deserialize(&mut &account.try_borrow_data()?[..])
+    }
 }
 
 #[derive(Clone)]
-pub struct PriceFeed (pyth_sdk::PriceFeed);
+pub struct PriceFeed {
+    feed: pyth_sdk::PriceFeed,
+    status: PriceStatus,
+}
+
+#[cfg(all(feature = "devnet", feature = "mainnet"))]
+compile_error!("Enable only one cluster feature; build mainnet with `--no-default-features --features mainnet`.");
+
+#[cfg(not(any(feature = "devnet", feature = "mainnet")))]
+compile_error!("Enable one of the `devnet`, `localnet` or `mainnet` features.");
+
+/// Pyth oracle program on solana devnet. Localnet validators clone it from devnet.
+#[cfg(feature = "devnet")]
+pub const PYTH_PROGRAM_ID: Pubkey = pubkey!("gSbePebfvPy7tRqimPoVecS2UsBvYv46ynrzWocc92s");
+
+/// Pyth oracle program on solana mainnet-beta.
+#[cfg(feature = "mainnet")]
+pub const PYTH_PROGRAM_ID: Pubkey = pubkey!("FsJ3A3u2vn5cTVofAjvy6y5kwABJAqYWpe4975bi2epH");
 
 impl anchor_lang::Owner for PriceFeed {
     fn owner() -> Pubkey {
-        // Make sure the owner is the pyth oracle account on solana devnet
-        let oracle_addr = "gSbePebfvPy7tRqimPoVecS2UsBvYv46ynrzWocc92s";
-        return  Pubkey::from_str(&oracle_addr).unwrap();
+        // Make sure the owner is the pyth oracle program of the target cluster
+        PYTH_PROGRAM_ID
     }
 }
 
@@ -30,7 +93,7 @@ impl anchor_lang::AccountDeserialize for PriceFeed {
         let zeros: [u8; 32] = [0; 32];
         let dummy_key = Pubkey::from(zeros);
         let feed = account.to_price_feed(&dummy_key);
-        return Ok(PriceFeed(feed));
+        Ok(PriceFeed { feed, status: account.agg.status })
     }
 }
 
@@ -44,6 +107,14 @@ impl Deref for PriceFeed {
     type Target = pyth_sdk::PriceFeed;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.feed
     }
 }
+
+impl PriceFeed {
+    /// Status of the latest aggregate price.
+    pub fn price_status(&self) -> PriceStatus {
+        self.status
+    }
+}
+
@@ -0,0 +1,86 @@
+use anchor_lang::prelude::*;
+
+use crate::ErrorCode;
+
+/// Number of cumulative price observations a market keeps.
+pub const TWAP_OBSERVATIONS: usize = 24;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct PriceObservation {
+    pub timestamp: i64,
+    pub cumulative_price: u128,
+}
+
+/// Time-weighted price accumulator of a market. Every price read folds the
+/// previous price in, weighted by the seconds it was current, and a ring
+/// buffer of snapshots lets callers average over any window it covers.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct TwapAccumulator {
+    /// Sum of `price * seconds` since the first update.
+    pub cumulative_price: u128,
+    pub last_price: u64,
+    pub last_update: i64,
+    pub observations: [PriceObservation; TWAP_OBSERVATIONS],
+    /// Index of the newest observation.
+    pub head: u8,
+}
+
+impl TwapAccumulator {
+    fn cumulative_at(&self, now: i64) -> Result<u128> {
+        let elapsed = now.saturating_sub(self.last_update).max(0) as u128;
+        (self.last_price as u128)
+            .checked_mul(elapsed)
+            .and_then(|weighted| weighted.checked_add(self.cumulative_price))
+            .ok_or(error!(ErrorCode::Overflow))
+    }
+
+    /// Makes `price` the current price at `now`, taking a new observation if
+    /// the newest one is at least `interval` seconds old.
+    pub fn update(&mut self, price: u64, now: i64, interval: u64) -> Result<()> {
+        if self.last_update == 0 {
+            self.last_price = price;
+            self.last_update = now;
+            self.observations[0] = PriceObservation { timestamp: now, cumulative_price: 0 };
+            return Ok(());
+        }
+        if now < self.last_update {
+            return Ok(());
+        }
+        self.cumulative_price = self.cumulative_at(now)?;
+        self.last_price = price;
+        self.last_update = now;
+        let newest = self.observations[self.head as usize];
+        if now.saturating_sub(newest.timestamp) >= interval as i64 {
+            self.head = ((self.head as usize + 1) % TWAP_OBSERVATIONS) as u8;
+            self.observations[self.head as usize] = PriceObservation {
+                timestamp: now,
+                cumulative_price: self.cumulative_price,
+            };
+        }
+        Ok(())
+    }
+
+    /// Time-weighted average price over at least the last `window` seconds,
+    /// measured from the newest observation taken before the window started.
+    pub fn twap(&self, now: i64, window: u64) -> Result<u64> {
+        if self.last_update == 0 {
+            return Err(error!(ErrorCode::TwapUnavailable));
+        }
+        let start = now.saturating_sub(window as i64);
+        let observation = (0..TWAP_OBSERVATIONS)
+            .map(|age| self.observations[(self.head as usize + TWAP_OBSERVATIONS - age) % TWAP_OBSERVATIONS])
+            .take_while(|observation| observation.timestamp != 0)
+            .find(|observation| observation.timestamp <= start)
+            .ok_or(error!(ErrorCode::TwapUnavailable))?;
+        let elapsed = now.saturating_sub(observation.timestamp);
+        if elapsed <= 0 {
+            return Ok(self.last_price);
+        }
+        let average = self
+            .cumulative_at(now)?
+            .checked_sub(observation.cumulative_price)
+            .ok_or(error!(ErrorCode::Overflow))?
+            / elapsed as u128;
+        u64::try_from(average).map_err(|_| error!(ErrorCode::Overflow))
+    }
+}
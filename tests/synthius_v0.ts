@@ -17,21 +17,95 @@ describe("synthius_v0", () => {
   const program = anchor.workspace.SynthiusV0 as Program<SynthiusV0>;
   const programId = program.programId;
   let example_price = "G7dySNGaxZ8y2E89aX1K6rFeBt2ZnYBqXuCu1k2Y9MEe";
+  // `anchor run test-mock` builds with the `mock-oracle` feature and sets
+  // MOCK_ORACLE=1 to price the market from a local mock account instead of
+  // the devnet Pyth feed. Tests that need the mock are skipped otherwise.
+  const useMockOracle = process.env.MOCK_ORACLE === "1";
+  const mockOracle = anchor.web3.Keypair.generate();
+  const oracleKey = useMockOracle ? mockOracle.publicKey : new anchor.web3.PublicKey(example_price);
+  const setMockPrice = (price: number) =>
+    program.methods.setMockOracle({
+      price: new anchor.BN(price),
+      conf: new anchor.BN(0),
+      expo: -2,
+      emaPrice: new anchor.BN(price),
+      emaConf: new anchor.BN(0),
+      publishTime: null,
+      status: { trading: {} },
+    }).accounts({
+      authority: payer.publicKey,
+      mockOracle: mockOracle.publicKey,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    }).signers([mockOracle]).rpc();
 
-  const longMintKeypair = anchor.web3.Keypair.generate();
-  const shortMintKeypair = anchor.web3.Keypair.generate();
   const collateralMintKeypair = anchor.web3.Keypair.generate();
 
-  const depositedAmount = new anchor.BN(1);
-  const dummyTokenAmount = new anchor.BN(5);
+  const depositedAmount = new anchor.BN(1_000);
+  // Opens can't reserve more notional than the pool holds.
+  const liquidityAmount = new anchor.BN(1_000_000);
+  const dummyTokenAmount = new anchor.BN(2_000_000);
 
-  const config = anchor.web3.Keypair.generate();
-  const [vaultKey, vaultBump] = PublicKey.findProgramAddressSync(
-    [Buffer.from(anchor.utils.bytes.utf8.encode("vault")), payer.publicKey.toBuffer()], programId
+  const [configKey] = PublicKey.findProgramAddressSync(
+    [Buffer.from(anchor.utils.bytes.utf8.encode("config"))], programId
+  );
+  const symbol = Array.from(Buffer.concat([Buffer.from("TSLA"), Buffer.alloc(12)]));
+  const [marketKey] = PublicKey.findProgramAddressSync(
+    [Buffer.from(anchor.utils.bytes.utf8.encode("market")), Buffer.from(symbol)], programId
+  );
+  const [longMintKey] = PublicKey.findProgramAddressSync(
+    [Buffer.from(anchor.utils.bytes.utf8.encode("long_mint")), marketKey.toBuffer()], programId
+  );
+  const [shortMintKey] = PublicKey.findProgramAddressSync(
+    [Buffer.from(anchor.utils.bytes.utf8.encode("short_mint")), marketKey.toBuffer()], programId
   );
-  const vaultWalletKey = PublicKey.findProgramAddressSync(
-    [Buffer.from(anchor.utils.bytes.utf8.encode("vault_wallet")), payer.publicKey.toBuffer()], programId
+  // Each position lives in its own vault keyed by owner, market and index.
+  const longPositionIndex = new anchor.BN(0);
+  const shortPositionIndex = new anchor.BN(1);
+  const findVault = (positionIndex: anchor.BN) => {
+    const [vault] = PublicKey.findProgramAddressSync(
+      [Buffer.from(anchor.utils.bytes.utf8.encode("vault")), payer.publicKey.toBuffer(),
+       marketKey.toBuffer(), positionIndex.toArrayLike(Buffer, "le", 8)], programId
+    );
+    const [vaultWallet] = PublicKey.findProgramAddressSync(
+      [Buffer.from(anchor.utils.bytes.utf8.encode("vault_wallet")), vault.toBuffer()], programId
+    );
+    return [vault, vaultWallet];
+  };
+  const [longVaultKey, longVaultWalletKey] = findVault(longPositionIndex);
+  const [shortVaultKey, shortVaultWalletKey] = findVault(shortPositionIndex);
+  const poolWalletKey = PublicKey.findProgramAddressSync(
+    [Buffer.from(anchor.utils.bytes.utf8.encode("pool_wallet")), collateralMintKeypair.publicKey.toBuffer()], programId
   ) [0];
+  const [poolKey] = PublicKey.findProgramAddressSync(
+    [Buffer.from(anchor.utils.bytes.utf8.encode("pool")), collateralMintKeypair.publicKey.toBuffer()], programId
+  );
+  const [lpMintKey] = PublicKey.findProgramAddressSync(
+    [Buffer.from(anchor.utils.bytes.utf8.encode("lp_mint")), poolKey.toBuffer()], programId
+  );
+  const [feeVaultKey] = PublicKey.findProgramAddressSync(
+    [Buffer.from(anchor.utils.bytes.utf8.encode("fee_vault")), collateralMintKeypair.publicKey.toBuffer()], programId
+  );
+  const [lockedLpKey] = PublicKey.findProgramAddressSync(
+    [Buffer.from(anchor.utils.bytes.utf8.encode("locked_lp")), poolKey.toBuffer()], programId
+  );
+  const feeSplit = { lpBps: 7000, insuranceBps: 2000, treasuryBps: 1000 };
+  const marketParams = {
+    maxLeverage: 5,
+    maintenanceMarginBps: 500,
+    liquidationFeeBps: 100,
+    maxOpenAge: new anchor.BN(60),
+    maxCloseAge: new anchor.BN(300),
+    entryPriceMode: { spot: {} },
+    liquidationPriceMode: { ema: {} },
+    circuitBreaker: { maxDeviationBps: 1000, window: new anchor.BN(300), cooldown: new anchor.BN(600), haltCloses: false },
+    twapInterval: new anchor.BN(60),
+    maxConfBps: 200,
+    schedule: { days: 0x7f, openMinute: 0, closeMinute: 1440, allowClosedExits: true },
+    conservativePricing: true,
+    openFeeBps: 10,
+    closeFeeBps: 10,
+    maxFundingRateBps: 10,
+  };
 
   const threadId = "liquidator-" + new Date().getTime() / 1000;
   const [threadAuthority] = PublicKey.findProgramAddressSync(
@@ -63,14 +137,99 @@ describe("synthius_v0", () => {
   it("Is initialized!", async () => {
     // Add your test here.
     const tx = await program.methods.initialize({
-      loanPriceFeedId: new anchor.web3.PublicKey(example_price)
+      maxLeverage: 10,
+      feeSplit,
     }).accounts({
       program: programId,
       payer: payer.publicKey,
-      config: config.publicKey,
+      config: configKey,
       systemProgram: anchor.web3.SystemProgram.programId,
-      vault: vaultKey, 
-    }).signers([config, programKey]).rpc();
+    }).signers([programKey]).rpc();
+    console.log("Your transaction signature", tx);
+  });
+
+  it("Rejects a second initialize", async () => {
+    try {
+      await program.methods.initialize({
+        maxLeverage: 10,
+        feeSplit,
+      }).accounts({
+        program: programId,
+        payer: payer.publicKey,
+        config: configKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      }).signers([programKey]).rpc();
+      assert.fail("The config must only be initialized once");
+    } catch (error) {
+      assert(error instanceof anchor.AnchorError);
+      assert.equal(error.error.errorCode.code, "ReInitialize");
+    }
+  });
+
+  it("Updates the config", async () => {
+    const tx = await program.methods.updateConfig({
+      maxLeverage: 10,
+      feeSplit,
+    }).accounts({
+      admin: payer.publicKey,
+      config: configKey,
+    }).rpc();
+    console.log("Your transaction signature", tx);
+  });
+
+  it("Rejects a fee split that doesn't add up to 100%", async () => {
+    try {
+      await program.methods.updateConfig({
+        maxLeverage: 10,
+        feeSplit: { lpBps: 7000, insuranceBps: 2000, treasuryBps: 2000 },
+      }).accounts({
+        admin: payer.publicKey,
+        config: configKey,
+      }).rpc();
+      assert.fail("The fee split must add up to 10000 bps");
+    } catch (error) {
+      assert(error instanceof anchor.AnchorError);
+      assert.equal(error.error.errorCode.code, "InvalidArgument");
+    }
+  });
+
+  it("Hands the admin over in two steps", async () => {
+    const newAdmin = anchor.web3.Keypair.generate();
+    await program.methods.proposeAdmin(newAdmin.publicKey)
+            .accounts({
+              admin: payer.publicKey,
+              config: configKey,
+            }).rpc();
+    try {
+      await program.methods.updateConfig({ maxLeverage: 20, feeSplit })
+            .accounts({
+              admin: newAdmin.publicKey,
+              config: configKey,
+            }).signers([newAdmin]).rpc();
+      assert.fail("A proposed admin must accept before it can update the config");
+    } catch (error) {
+      assert(error instanceof anchor.AnchorError);
+      assert.equal(error.error.errorCode.code, "Unauthorized");
+    }
+    await program.methods.acceptAdmin()
+            .accounts({
+              pendingAdmin: newAdmin.publicKey,
+              config: configKey,
+            }).signers([newAdmin]).rpc();
+    // Hand it back so the remaining tests can keep using the payer as admin.
+    await program.methods.proposeAdmin(payer.publicKey)
+            .accounts({
+              admin: newAdmin.publicKey,
+              config: configKey,
+            }).signers([newAdmin]).rpc();
+    const tx = await program.methods.acceptAdmin()
+            .accounts({
+              pendingAdmin: payer.publicKey,
+              config: configKey,
+            }).rpc();
+    const adminConfig = await program.account.adminConfig.fetch(configKey);
+    assert(adminConfig.admin.equals(payer.publicKey));
+    assert.equal(adminConfig.pendingAdmin, null);
     console.log("Your transaction signature", tx);
   });
 
@@ -90,52 +249,473 @@ describe("synthius_v0", () => {
     console.log("Your transaction signature", tx);
   });
 
+  it("Adds a market", async () => {
+    if (useMockOracle) {
+      await setMockPrice(10000);
+    }
+    let tx = await program.methods.addMarket(symbol, useMockOracle ? { mock: {} } : { pyth: {} }, null, marketParams).accounts({
+      admin: payer.publicKey,
+      payer: payer.publicKey,
+      config: configKey,
+      market: marketKey,
+      oracleAccount: oracleKey,
+      fallbackOracleAccount: null,
+      collateralTokenMint: collateralMintKeypair.publicKey,
+      longTokenMint: longMintKey,
+      shortTokenMint: shortMintKey,
+      systemProgram: anchor.web3.SystemProgram.programId,
+      tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+    }).rpc();
+    console.log("Your transaction signature", tx);
+  });
+
+  it("Rejects a maintenance margin a max leverage position would open below", async () => {
+    try {
+      await program.methods.updateMarket({ ...marketParams, maintenanceMarginBps: 2000 })
+            .accounts({
+              admin: payer.publicKey,
+              config: configKey,
+              market: marketKey,
+            }).rpc();
+      assert.fail("maintenance margin times max leverage must stay below 100%");
+    } catch (error) {
+      assert(error instanceof anchor.AnchorError);
+      assert.equal(error.error.errorCode.code, "InvalidArgument");
+    }
+  });
+
+  it("Initializes the pool", async () => {
+    let tx = await program.methods.initializePool()
+              .accounts({
+                admin: payer.publicKey,
+                config: configKey,
+                payer: payer.publicKey,
+                systemProgram: anchor.web3.SystemProgram.programId,
+                tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+                collateralTokenMint: collateralMintKeypair.publicKey,
+                pool: poolKey,
+                lpTokenMint: lpMintKey,
+                poolWallet: poolWalletKey,
+                feeVault: feeVaultKey,
+                lockedLpTokenAccount: lockedLpKey,
+              }).rpc();
+    console.log("Your transaction signature", tx);
+  });
+
+  it("Adds liquidity",async () => {
+    const associatedTokenAddressCollateral =
+          await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
+    const associatedTokenAddressLpToken =
+          await anchor.utils.token.associatedAddress({mint: lpMintKey, owner: payer.publicKey});
+
+    let tx = await program.methods.addLiquidity(liquidityAmount)
+              .accounts({
+                config: configKey,
+                payer: payer.publicKey,
+                systemProgram: anchor.web3.SystemProgram.programId,
+                tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
+                collateralTokenMint: collateralMintKeypair.publicKey,
+                collateralTokenAccount: associatedTokenAddressCollateral,
+                pool: poolKey,
+                lpTokenMint: lpMintKey,
+                lpTokenAccount: associatedTokenAddressLpToken,
+                poolWallet: poolWalletKey,
+                lockedLpTokenAccount: lockedLpKey,
+              }).signers([payer.payer]).rpc();
+    console.log("Your transaction signature", tx);
+    const locked = await provider.connection.getTokenAccountBalance(lockedLpKey);
+    const shares = await provider.connection.getTokenAccountBalance(associatedTokenAddressLpToken);
+    assert(new anchor.BN(locked.value.amount).eqn(1_000));
+    assert(new anchor.BN(shares.value.amount).eq(liquidityAmount.subn(1_000)));
+  });
+
   it("Buys Long", async () => {
     const associatedTokenAddressCollateral =
           await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
     const associatedTokenAddressLongToken = 
-          await anchor.utils.token.associatedAddress({mint: longMintKeypair.publicKey, owner: payer.publicKey});
+          await anchor.utils.token.associatedAddress({mint: longMintKey, owner: payer.publicKey});
 
-    let tx = await program.methods.buyLong(depositedAmount)
+    let tx = await program.methods.buyLong(longPositionIndex, depositedAmount, 2, null)
             .accounts({
-              config: config.publicKey,
-              pythLoanAccount: new anchor.web3.PublicKey(example_price),
+              config: configKey,
+              poolWallet: poolWalletKey,
+              pool: poolKey,
+              feeVault: feeVaultKey,
+              market: marketKey,
+              oracleAccount: oracleKey,
+              fallbackOracleAccount: null,
               payer: payer.publicKey,
               systemProgram: anchor.web3.SystemProgram.programId,
               tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
               associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
-              longTokenMint: longMintKeypair.publicKey,
-              mintAuthority: payer.publicKey,
+              longTokenMint: longMintKey,
               longTokenAccount: associatedTokenAddressLongToken,
-              vault: vaultKey,
+              vault: longVaultKey,
+              collateralTokenMint: collateralMintKeypair.publicKey,
+              collateralTokenAccount: associatedTokenAddressCollateral,
+              vaultWallet: longVaultWalletKey
+            }).signers([payer.payer]).rpc();
+    console.log("Your transaction signature", tx);
+  });
+
+  it("Tracks open interest and snapshots the funding index", async () => {
+    const market = await program.account.market.fetch(marketKey);
+    const vault = await program.account.vault.fetch(longVaultKey);
+    assert(market.funding.longOpenInterest.eq(vault.collateralLocked));
+    assert(market.funding.shortOpenInterest.isZero());
+    assert(vault.fundingIndex.eq(market.funding.cumulativeIndex));
+  });
+
+  it("Accrues funding before updating the market params", async () => {
+    const before = await program.account.market.fetch(marketKey);
+    let tx = await program.methods.updateMarket(marketParams)
+            .accounts({
+              admin: payer.publicKey,
+              config: configKey,
+              market: marketKey,
+            }).rpc();
+    console.log("Your transaction signature", tx);
+    const after = await program.account.market.fetch(marketKey);
+    assert(after.funding.lastUpdate.gte(before.funding.lastUpdate));
+    // Longs are the only open interest, so they pay funding.
+    assert(after.funding.cumulativeIndex.gte(before.funding.cumulativeIndex));
+  });
+
+  it("Increases the long position at a weighted entry price", async () => {
+    const associatedTokenAddressCollateral =
+          await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
+    const associatedTokenAddressLongToken = 
+          await anchor.utils.token.associatedAddress({mint: longMintKey, owner: payer.publicKey});
+
+    let tx = await program.methods.buyLong(longPositionIndex, depositedAmount, 2, null)
+            .accounts({
+              config: configKey,
+              poolWallet: poolWalletKey,
+              pool: poolKey,
+              feeVault: feeVaultKey,
+              market: marketKey,
+              oracleAccount: oracleKey,
+              fallbackOracleAccount: null,
+              payer: payer.publicKey,
+              systemProgram: anchor.web3.SystemProgram.programId,
+              tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+              associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
+              longTokenMint: longMintKey,
+              longTokenAccount: associatedTokenAddressLongToken,
+              vault: longVaultKey,
+              collateralTokenMint: collateralMintKeypair.publicKey,
+              collateralTokenAccount: associatedTokenAddressCollateral,
+              vaultWallet: longVaultWalletKey
+            }).signers([payer.payer]).rpc();
+    console.log("Your transaction signature", tx);
+    const vault = await program.account.vault.fetch(longVaultKey);
+    const openFee = depositedAmount.muln(2).muln(marketParams.openFeeBps).divn(10_000);
+    assert(vault.amount.eq(depositedAmount.sub(openFee).muln(2)));
+  });
+
+  it("Rejects withdrawing liquidity that backs open positions", async () => {
+    const associatedTokenAddressCollateral =
+          await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
+    const associatedTokenAddressLpToken =
+          await anchor.utils.token.associatedAddress({mint: lpMintKey, owner: payer.publicKey});
+    const shares = await provider.connection.getTokenAccountBalance(associatedTokenAddressLpToken);
+    const pool = await program.account.pool.fetch(poolKey);
+    assert(pool.reserved.gtn(0));
+    try {
+      await program.methods.removeLiquidity(new anchor.BN(shares.value.amount))
+              .accounts({
+                config: configKey,
+                payer: payer.publicKey,
+                systemProgram: anchor.web3.SystemProgram.programId,
+                tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
+                collateralTokenMint: collateralMintKeypair.publicKey,
+                collateralTokenAccount: associatedTokenAddressCollateral,
+                pool: poolKey,
+                lpTokenMint: lpMintKey,
+                lpTokenAccount: associatedTokenAddressLpToken,
+                poolWallet: poolWalletKey
+              }).signers([payer.payer]).rpc();
+      assert.fail("Withdrawals must leave the open interest reserved");
+    } catch (error) {
+      assert(error instanceof anchor.AnchorError);
+      assert.equal(error.error.errorCode.code, "InsufficientLiquidity");
+    }
+  });
+
+  it("Rejects a deposit that mints no shares", async () => {
+    const associatedTokenAddressCollateral =
+          await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
+    const associatedTokenAddressLpToken =
+          await anchor.utils.token.associatedAddress({mint: lpMintKey, owner: payer.publicKey});
+    // The open fees left in the wallet price a share above one unit.
+    try {
+      await program.methods.addLiquidity(new anchor.BN(1))
+              .accounts({
+                config: configKey,
+                payer: payer.publicKey,
+                systemProgram: anchor.web3.SystemProgram.programId,
+                tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
+                collateralTokenMint: collateralMintKeypair.publicKey,
+                collateralTokenAccount: associatedTokenAddressCollateral,
+                pool: poolKey,
+                lpTokenMint: lpMintKey,
+                lpTokenAccount: associatedTokenAddressLpToken,
+                poolWallet: poolWalletKey,
+                lockedLpTokenAccount: lockedLpKey,
+              }).signers([payer.payer]).rpc();
+      assert.fail("A deposit must not be taken for zero shares");
+    } catch (error) {
+      assert(error instanceof anchor.AnchorError);
+      assert.equal(error.error.errorCode.code, "InvalidArgument");
+    }
+  });
+
+  it("Rejects flipping an open long to short", async () => {
+    const associatedTokenAddressCollateral =
+          await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
+    const associatedTokenAddressShortToken = 
+          await anchor.utils.token.associatedAddress({mint: shortMintKey, owner: payer.publicKey});
+
+    try {
+      await program.methods.buyShort(longPositionIndex, depositedAmount, null, null)
+            .accounts({
+              config: configKey,
+              poolWallet: poolWalletKey,
+              pool: poolKey,
+              feeVault: feeVaultKey,
+              market: marketKey,
+              oracleAccount: oracleKey,
+              fallbackOracleAccount: null,
+              payer: payer.publicKey,
+              systemProgram: anchor.web3.SystemProgram.programId,
+              tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+              associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
+              shortTokenMint: shortMintKey,
+              shortTokenAccount: associatedTokenAddressShortToken,
+              vault: longVaultKey,
+              collateralTokenMint: collateralMintKeypair.publicKey,
+              collateralTokenAccount: associatedTokenAddressCollateral,
+              vaultWallet: longVaultWalletKey
+            }).signers([payer.payer]).rpc();
+      assert.fail("An open long must not be flipped to short");
+    } catch (error) {
+      assert(error instanceof anchor.AnchorError);
+      assert.equal(error.error.errorCode.code, "PositionDirectionMismatch");
+    }
+  });
+
+  it("Rejects a short open below the acceptable price", async () => {
+    const associatedTokenAddressCollateral =
+          await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
+    const associatedTokenAddressShortToken = 
+          await anchor.utils.token.associatedAddress({mint: shortMintKey, owner: payer.publicKey});
+
+    try {
+      await program.methods.buyShort(shortPositionIndex, depositedAmount, null, new anchor.BN("18446744073709551615"))
+            .accounts({
+              config: configKey,
+              poolWallet: poolWalletKey,
+              pool: poolKey,
+              feeVault: feeVaultKey,
+              market: marketKey,
+              oracleAccount: oracleKey,
+              fallbackOracleAccount: null,
+              payer: payer.publicKey,
+              systemProgram: anchor.web3.SystemProgram.programId,
+              tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+              associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
+              shortTokenMint: shortMintKey,
+              shortTokenAccount: associatedTokenAddressShortToken,
+              vault: shortVaultKey,
+              collateralTokenMint: collateralMintKeypair.publicKey,
+              collateralTokenAccount: associatedTokenAddressCollateral,
+              vaultWallet: shortVaultWalletKey
+            }).signers([payer.payer]).rpc();
+      assert.fail("A short must not open below the acceptable price");
+    } catch (error) {
+      assert(error instanceof anchor.AnchorError);
+      assert.equal(error.error.errorCode.code, "SlippageExceeded");
+    }
+  });
+  it("Rejects an open the pool can't back", async () => {
+    const associatedTokenAddressCollateral =
+          await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
+    const associatedTokenAddressShortToken = 
+          await anchor.utils.token.associatedAddress({mint: shortMintKey, owner: payer.publicKey});
+
+    try {
+      await program.methods.buyShort(shortPositionIndex, new anchor.BN(600_000), 2, null)
+            .accounts({
+              config: configKey,
+              poolWallet: poolWalletKey,
+              pool: poolKey,
+              feeVault: feeVaultKey,
+              market: marketKey,
+              oracleAccount: oracleKey,
+              fallbackOracleAccount: null,
+              payer: payer.publicKey,
+              systemProgram: anchor.web3.SystemProgram.programId,
+              tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+              associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
+              shortTokenMint: shortMintKey,
+              shortTokenAccount: associatedTokenAddressShortToken,
+              vault: shortVaultKey,
+              collateralTokenMint: collateralMintKeypair.publicKey,
+              collateralTokenAccount: associatedTokenAddressCollateral,
+              vaultWallet: shortVaultWalletKey
+            }).signers([payer.payer]).rpc();
+      assert.fail("Opens must not reserve more than the pool holds");
+    } catch (error) {
+      assert(error instanceof anchor.AnchorError);
+      assert.equal(error.error.errorCode.code, "InsufficientLiquidity");
+    }
+  });
+
+  it("Rejects opens while paused globally or per market", async () => {
+    const associatedTokenAddressCollateral =
+          await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
+    const associatedTokenAddressShortToken = 
+          await anchor.utils.token.associatedAddress({mint: shortMintKey, owner: payer.publicKey});
+    const pauseOpens = { opens: true, closes: false, deposits: false, withdrawals: false, liquidations: false };
+    const resume = { opens: false, closes: false, deposits: false, withdrawals: false, liquidations: false };
+
+    await program.methods.setGlobalPause(pauseOpens)
+            .accounts({
+              admin: payer.publicKey,
+              config: configKey,
+            }).rpc();
+    try {
+      await program.methods.buyShort(shortPositionIndex, depositedAmount, null, null)
+            .accounts({
+              config: configKey,
+              poolWallet: poolWalletKey,
+              pool: poolKey,
+              feeVault: feeVaultKey,
+              market: marketKey,
+              oracleAccount: oracleKey,
+              fallbackOracleAccount: null,
+              payer: payer.publicKey,
+              systemProgram: anchor.web3.SystemProgram.programId,
+              tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+              associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
+              shortTokenMint: shortMintKey,
+              shortTokenAccount: associatedTokenAddressShortToken,
+              vault: shortVaultKey,
               collateralTokenMint: collateralMintKeypair.publicKey,
               collateralTokenAccount: associatedTokenAddressCollateral,
-              vaultWallet: vaultWalletKey
-            }).signers([payer.payer, longMintKeypair]).rpc();
+              vaultWallet: shortVaultWalletKey
+            }).signers([payer.payer]).rpc();
+      assert.fail("Opens must be rejected while paused globally");
+    } catch (error) {
+      assert(error instanceof anchor.AnchorError);
+      assert.equal(error.error.errorCode.code, "Paused");
+    }
+    await program.methods.setGlobalPause(resume)
+            .accounts({
+              admin: payer.publicKey,
+              config: configKey,
+            }).rpc();
+
+    await program.methods.setMarketPause(pauseOpens)
+            .accounts({
+              admin: payer.publicKey,
+              config: configKey,
+              market: marketKey,
+            }).rpc();
+    try {
+      await program.methods.buyShort(shortPositionIndex, depositedAmount, null, null)
+            .accounts({
+              config: configKey,
+              poolWallet: poolWalletKey,
+              pool: poolKey,
+              feeVault: feeVaultKey,
+              market: marketKey,
+              oracleAccount: oracleKey,
+              fallbackOracleAccount: null,
+              payer: payer.publicKey,
+              systemProgram: anchor.web3.SystemProgram.programId,
+              tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+              associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
+              shortTokenMint: shortMintKey,
+              shortTokenAccount: associatedTokenAddressShortToken,
+              vault: shortVaultKey,
+              collateralTokenMint: collateralMintKeypair.publicKey,
+              collateralTokenAccount: associatedTokenAddressCollateral,
+              vaultWallet: shortVaultWalletKey
+            }).signers([payer.payer]).rpc();
+      assert.fail("Opens must be rejected while the market is paused");
+    } catch (error) {
+      assert(error instanceof anchor.AnchorError);
+      assert.equal(error.error.errorCode.code, "Paused");
+    }
+    let tx = await program.methods.setMarketPause(resume)
+            .accounts({
+              admin: payer.publicKey,
+              config: configKey,
+              market: marketKey,
+            }).rpc();
     console.log("Your transaction signature", tx);
   });
 
+  it("Partially closes the long position", async () => {
+    const associatedTokenAddressCollateral =
+          await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
+    const associatedTokenAddressLongToken = 
+          await anchor.utils.token.associatedAddress({mint: longMintKey, owner: payer.publicKey});
+    const before = await program.account.vault.fetch(longVaultKey);
+    const closedSize = before.collateralLocked.divn(2);
+
+    let tx = await program.methods.decreasePosition(closedSize, null)
+              .accounts({
+                config: configKey,
+                pool: poolKey,
+                feeVault: feeVaultKey,
+                market: marketKey,
+                oracleAccount: oracleKey,
+                fallbackOracleAccount: null,
+                payer: payer.publicKey,
+                tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+                vault: longVaultKey,
+                positionTokenMint: longMintKey,
+                positionTokenAccount: associatedTokenAddressLongToken,
+                collateralTokenMint: collateralMintKeypair.publicKey,
+                collateralTokenAccount: associatedTokenAddressCollateral,
+                vaultWallet: longVaultWalletKey,
+                poolWallet: poolWalletKey,
+              }).signers([payer.payer]).rpc();
+    console.log("Your transaction signature", tx);
+    const after = await program.account.vault.fetch(longVaultKey);
+    assert(after.collateralLocked.eq(before.collateralLocked.sub(closedSize)));
+  });
+
   it("Sells Long", async() => {
     const associatedTokenAddressCollateral =
           await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
     const associatedTokenAddressLongToken = 
-          await anchor.utils.token.associatedAddress({mint: longMintKeypair.publicKey, owner: payer.publicKey});
+          await anchor.utils.token.associatedAddress({mint: longMintKey, owner: payer.publicKey});
           
-    let tx = await program.methods.sellLong(vaultBump, payer.publicKey)
+    let tx = await program.methods.sellLong(null)
               .accounts({
-                config: config.publicKey,
-                pythLoanAccount: new anchor.web3.PublicKey(example_price),
+                config: configKey,
+                pool: poolKey,
+                feeVault: feeVaultKey,
+                market: marketKey,
+                oracleAccount: oracleKey,
+                fallbackOracleAccount: null,
                 payer: payer.publicKey,
                 systemProgram: anchor.web3.SystemProgram.programId,
                 tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
                 associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
-                longTokenMint: longMintKeypair.publicKey,
-                mintAuthority: payer.publicKey,
+                longTokenMint: longMintKey,
                 longTokenAccount: associatedTokenAddressLongToken,
                 collateralTokenMint: collateralMintKeypair.publicKey,
                 collateralTokenAccount: associatedTokenAddressCollateral,
-                vaultWallet: vaultWalletKey,
-                vault: vaultKey,
+                vaultWallet: longVaultWalletKey,
+                poolWallet: poolWalletKey,
+                vault: longVaultKey,
               }).signers([payer.payer]).rpc();
     console.log("Your transaction signature", tx);
   });
@@ -144,24 +724,28 @@ describe("synthius_v0", () => {
     const associatedTokenAddressCollateral =
           await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
     const associatedTokenAddressShortToken = 
-          await anchor.utils.token.associatedAddress({mint: shortMintKeypair.publicKey, owner: payer.publicKey});
+          await anchor.utils.token.associatedAddress({mint: shortMintKey, owner: payer.publicKey});
     
-    let tx = await program.methods.buyShort(depositedAmount)
+    let tx = await program.methods.buyShort(shortPositionIndex, depositedAmount, null, null)
             .accounts({
-              config: config.publicKey,
-              pythLoanAccount: new anchor.web3.PublicKey(example_price),
+              config: configKey,
+              poolWallet: poolWalletKey,
+              pool: poolKey,
+              feeVault: feeVaultKey,
+              market: marketKey,
+              oracleAccount: oracleKey,
+              fallbackOracleAccount: null,
               payer: payer.publicKey,
               systemProgram: anchor.web3.SystemProgram.programId,
               tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
               associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
-              shortTokenMint: shortMintKeypair.publicKey,
-              mintAuthority: payer.publicKey,
+              shortTokenMint: shortMintKey,
               shortTokenAccount: associatedTokenAddressShortToken,
-              vault: vaultKey,
+              vault: shortVaultKey,
               collateralTokenMint: collateralMintKeypair.publicKey,
               collateralTokenAccount: associatedTokenAddressCollateral,
-              vaultWallet: vaultWalletKey
-            }).signers([payer.payer, shortMintKeypair]).rpc();
+              vaultWallet: shortVaultWalletKey
+            }).signers([payer.payer]).rpc();
     console.log("Your transaction signature", tx);
   });
 
@@ -169,68 +753,296 @@ describe("synthius_v0", () => {
     const associatedTokenAddressCollateral =
           await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
     const associatedTokenAddressShortToken = 
-          await anchor.utils.token.associatedAddress({mint: shortMintKeypair.publicKey, owner: payer.publicKey});
-    let tx = await program.methods.sellShort(vaultBump, payer.publicKey)
+          await anchor.utils.token.associatedAddress({mint: shortMintKey, owner: payer.publicKey});
+    let tx = await program.methods.sellShort(null)
             .accounts({
-              config: config.publicKey,
-              pythLoanAccount: new anchor.web3.PublicKey(example_price),
+              config: configKey,
+              pool: poolKey,
+              feeVault: feeVaultKey,
+              market: marketKey,
+              oracleAccount: oracleKey,
+              fallbackOracleAccount: null,
               payer: payer.publicKey,
               systemProgram: anchor.web3.SystemProgram.programId,
               tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
               associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
-              vault: vaultKey,
-              shortTokenMint: shortMintKeypair.publicKey,
-              mintAuthority: payer.publicKey,
+              vault: shortVaultKey,
+              shortTokenMint: shortMintKey,
               shortTokenAccount: associatedTokenAddressShortToken,
               collateralTokenMint: collateralMintKeypair.publicKey,
               collateralTokenAccount: associatedTokenAddressCollateral,
-              vaultWallet: vaultWalletKey,
+              vaultWallet: shortVaultWalletKey,
+              poolWallet: poolWalletKey,
             }).signers([payer.payer]).rpc();
     console.log("Your transaction signature", tx);
   });
 
-  it("Adds liquidity",async () => {
+  it("Reads the market TWAP", async () => {
+    const twap = await program.methods.twap(new anchor.BN(0))
+            .accounts({
+              market: marketKey,
+            }).view();
+    assert(twap.gtn(0));
+  });
+
+  it("Removes liquidity", async () => {
     const associatedTokenAddressCollateral =
           await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
+    const associatedTokenAddressLpToken =
+          await anchor.utils.token.associatedAddress({mint: lpMintKey, owner: payer.publicKey});
+    const shares = await provider.connection.getTokenAccountBalance(associatedTokenAddressLpToken);
 
-    let tx = await program.methods.addLiquidity(depositedAmount)
+    let tx = await program.methods.removeLiquidity(new anchor.BN(shares.value.amount).divn(2))
               .accounts({
+                config: configKey,
                 payer: payer.publicKey,
                 systemProgram: anchor.web3.SystemProgram.programId,
                 tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
                 associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
                 collateralTokenMint: collateralMintKeypair.publicKey,
                 collateralTokenAccount: associatedTokenAddressCollateral,
-                vault: vaultKey,
-                vaultWallet: vaultWalletKey
+                pool: poolKey,
+                lpTokenMint: lpMintKey,
+                lpTokenAccount: associatedTokenAddressLpToken,
+                poolWallet: poolWalletKey
               }).signers([payer.payer]).rpc();
     console.log("Your transaction signature", tx);
-  })
+  });
 
-  it ("Liquidates every 24 hours", async () => {
+  it("Liquidates a long after the mock price drops", async function () {
+    if (!useMockOracle) {
+      this.skip();
+    }
+    const liquidatedPositionIndex = new anchor.BN(2);
+    const [vault, vaultWallet] = findVault(liquidatedPositionIndex);
     const associatedTokenAddressCollateral =
           await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
-    const associatedTokenAddressShortToken = 
-          await anchor.utils.token.associatedAddress({mint: shortMintKeypair.publicKey, owner: payer.publicKey});
-    const associatedTokenAddressLongToken = 
-          await anchor.utils.token.associatedAddress({mint: longMintKeypair.publicKey, owner: payer.publicKey});
-    let tx = await program.methods.trigger(Buffer.from(threadId),vaultBump, payer.publicKey)
+    const associatedTokenAddressLongToken =
+          await anchor.utils.token.associatedAddress({mint: longMintKey, owner: payer.publicKey});
+    await setMockPrice(10000);
+    await program.methods.buyLong(liquidatedPositionIndex, new anchor.BN(50_000), 5, null)
             .accounts({
-              config: config.publicKey,
-              pythLoanAccount: new anchor.web3.PublicKey(example_price),
+              config: configKey,
+              poolWallet: poolWalletKey,
+              pool: poolKey,
+              feeVault: feeVaultKey,
+              market: marketKey,
+              oracleAccount: oracleKey,
+              fallbackOracleAccount: null,
               payer: payer.publicKey,
               systemProgram: anchor.web3.SystemProgram.programId,
               tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
               associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
-              vault: vaultKey,
-              shortTokenMint: shortMintKeypair.publicKey,
-              mintAuthority: payer.publicKey,
-              shortTokenAccount: associatedTokenAddressShortToken,
-              longTokenMint: longMintKeypair.publicKey,
+              longTokenMint: longMintKey,
+              longTokenAccount: associatedTokenAddressLongToken,
+              vault: vault,
+              collateralTokenMint: collateralMintKeypair.publicKey,
+              collateralTokenAccount: associatedTokenAddressCollateral,
+              vaultWallet: vaultWallet
+            }).signers([payer.payer]).rpc();
+    const before = await provider.connection.getTokenAccountBalance(associatedTokenAddressLongToken);
+    await setMockPrice(5000);
+    const keeper = anchor.web3.Keypair.generate();
+    let tx = await program.methods.liquidataLong()
+            .accounts({
+              config: configKey,
+              market: marketKey,
+              oracleAccount: oracleKey,
+              fallbackOracleAccount: null,
+              liquidator: keeper.publicKey,
+              owner: payer.publicKey,
+              tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+              vault: vault,
+              longTokenMint: longMintKey,
+              longTokenAccount: associatedTokenAddressLongToken,
+              collateralTokenMint: collateralMintKeypair.publicKey,
+              pool: poolKey,
+              vaultWallet: vaultWallet,
+              poolWallet: poolWalletKey,
+              liquidatorTokenAccount: associatedTokenAddressCollateral,
+            }).signers([keeper]).rpc();
+    const liquidated = await program.account.vault.fetch(vault);
+    assert(liquidated.amount.isZero());
+    const after = await provider.connection.getTokenAccountBalance(associatedTokenAddressLongToken);
+    assert(new anchor.BN(before.value.amount).sub(new anchor.BN(after.value.amount)).eqn(250_000));
+    console.log("Your transaction signature", tx);
+  });
+
+  it("Collects the treasury share of the fees", async () => {
+    const associatedTokenAddressCollateral =
+          await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
+    const pool = await program.account.pool.fetch(poolKey);
+    const before = await provider.connection.getTokenAccountBalance(associatedTokenAddressCollateral);
+    let tx = await program.methods.collectFees()
+            .accounts({
+              admin: payer.publicKey,
+              config: configKey,
+              tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+              collateralTokenMint: collateralMintKeypair.publicKey,
+              pool: poolKey,
+              feeVault: feeVaultKey,
+              treasuryTokenAccount: associatedTokenAddressCollateral,
+            }).rpc();
+    console.log("Your transaction signature", tx);
+    const after = await provider.connection.getTokenAccountBalance(associatedTokenAddressCollateral);
+    assert(new anchor.BN(after.value.amount).sub(new anchor.BN(before.value.amount)).eq(pool.treasuryFees));
+    const collected = await program.account.pool.fetch(poolKey);
+    assert(collected.treasuryFees.isZero());
+    assert(collected.insuranceFees.eq(pool.insuranceFees));
+  });
+
+  it("Trips the circuit breaker when the mock price jumps", async function () {
+    if (!useMockOracle) {
+      this.skip();
+    }
+    await program.methods.updatePrice()
+            .accounts({
+              market: marketKey,
+              oracleAccount: oracleKey,
+              fallbackOracleAccount: null,
+            }).rpc();
+    let market = await program.account.market.fetch(marketKey);
+    assert(!market.circuitBreaker.trippedAt.isZero());
+    let tx = await program.methods.resetCircuitBreaker()
+            .accounts({
+              admin: payer.publicKey,
+              config: configKey,
+              market: marketKey,
+            }).rpc();
+    market = await program.account.market.fetch(marketKey);
+    assert(market.circuitBreaker.trippedAt.isZero());
+    await setMockPrice(10000);
+    console.log("Your transaction signature", tx);
+  });
+
+  it("Rejects an open that would trip the circuit breaker", async function () {
+    if (!useMockOracle) {
+      this.skip();
+    }
+    const rejectedPositionIndex = new anchor.BN(4);
+    const [vault, vaultWallet] = findVault(rejectedPositionIndex);
+    const associatedTokenAddressCollateral =
+          await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
+    const associatedTokenAddressLongToken =
+          await anchor.utils.token.associatedAddress({mint: longMintKey, owner: payer.publicKey});
+    await program.methods.updatePrice()
+            .accounts({
+              market: marketKey,
+              oracleAccount: oracleKey,
+              fallbackOracleAccount: null,
+            }).rpc();
+    const before = await program.account.market.fetch(marketKey);
+    await setMockPrice(20000);
+    try {
+      await program.methods.buyLong(rejectedPositionIndex, new anchor.BN(100_000), 1, null)
+              .accounts({
+                config: configKey,
+                poolWallet: poolWalletKey,
+                pool: poolKey,
+                feeVault: feeVaultKey,
+                market: marketKey,
+                oracleAccount: oracleKey,
+                fallbackOracleAccount: null,
+                payer: payer.publicKey,
+                systemProgram: anchor.web3.SystemProgram.programId,
+                tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+                associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
+                longTokenMint: longMintKey,
+                longTokenAccount: associatedTokenAddressLongToken,
+                vault: vault,
+                collateralTokenMint: collateralMintKeypair.publicKey,
+                collateralTokenAccount: associatedTokenAddressCollateral,
+                vaultWallet: vaultWallet
+              }).signers([payer.payer]).rpc();
+      assert.fail("An open must fail when the price would trip the circuit breaker");
+    } catch (error) {
+      assert(error instanceof anchor.AnchorError);
+      assert.equal(error.error.errorCode.code, "CircuitBreakerTripped");
+    }
+    // Only the update_price crank persists a trip.
+    const market = await program.account.market.fetch(marketKey);
+    assert(market.circuitBreaker.trippedAt.isZero());
+    assert(market.circuitBreaker.lastPrice.eq(before.circuitBreaker.lastPrice));
+    assert.equal(await program.account.vault.fetchNullable(vault), null);
+    await setMockPrice(10000);
+  });
+
+  it("Rejects liquidating a healthy position", async () => {
+    const healthyPositionIndex = new anchor.BN(3);
+    const [vault, vaultWallet] = findVault(healthyPositionIndex);
+    const associatedTokenAddressCollateral =
+          await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
+    const associatedTokenAddressLongToken =
+          await anchor.utils.token.associatedAddress({mint: longMintKey, owner: payer.publicKey});
+    await program.methods.buyLong(healthyPositionIndex, new anchor.BN(100_000), 1, null)
+            .accounts({
+              config: configKey,
+              poolWallet: poolWalletKey,
+              pool: poolKey,
+              feeVault: feeVaultKey,
+              market: marketKey,
+              oracleAccount: oracleKey,
+              fallbackOracleAccount: null,
+              payer: payer.publicKey,
+              systemProgram: anchor.web3.SystemProgram.programId,
+              tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+              associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
+              longTokenMint: longMintKey,
               longTokenAccount: associatedTokenAddressLongToken,
+              vault: vault,
+              collateralTokenMint: collateralMintKeypair.publicKey,
+              collateralTokenAccount: associatedTokenAddressCollateral,
+              vaultWallet: vaultWallet
+            }).signers([payer.payer]).rpc();
+    const opened = await program.account.vault.fetch(vault);
+    assert(opened.collateralLocked.gtn(0));
+    const keeper = anchor.web3.Keypair.generate();
+    try {
+      await program.methods.liquidataLong()
+            .accounts({
+              config: configKey,
+              market: marketKey,
+              oracleAccount: oracleKey,
+              fallbackOracleAccount: null,
+              liquidator: keeper.publicKey,
+              owner: payer.publicKey,
+              tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+              vault: vault,
+              longTokenMint: longMintKey,
+              longTokenAccount: associatedTokenAddressLongToken,
+              collateralTokenMint: collateralMintKeypair.publicKey,
+              pool: poolKey,
+              vaultWallet: vaultWallet,
+              poolWallet: poolWalletKey,
+              liquidatorTokenAccount: associatedTokenAddressCollateral,
+            }).signers([keeper]).rpc();
+      assert.fail("A healthy position must not be liquidated");
+    } catch (error) {
+      assert(error instanceof anchor.AnchorError);
+      assert.equal(error.error.errorCode.code, "PositionNotLiquidatable");
+    }
+  });
+
+  it ("Liquidates every 24 hours", async () => {
+    const associatedTokenAddressCollateral =
+          await anchor.utils.token.associatedAddress({mint: collateralMintKeypair.publicKey, owner: payer.publicKey});
+    let tx = await program.methods.trigger(Buffer.from(threadId))
+            .accounts({
+              config: configKey,
+              market: marketKey,
+              oracleAccount: oracleKey,
+              payer: payer.publicKey,
+              systemProgram: anchor.web3.SystemProgram.programId,
+              tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+              associatedTokenProgram: ASSOCIATED_PROGRAM_ID,
+              vault: longVaultKey,
+              positionTokenMint: longMintKey,
+              positionTokenAccount: await anchor.utils.token.associatedAddress({mint: longMintKey, owner: payer.publicKey}),
               collateralTokenMint: collateralMintKeypair.publicKey,
+              pool: poolKey,
               collateralTokenAccount: associatedTokenAddressCollateral,
-              vaultWallet: vaultWalletKey,
+              vaultWallet: longVaultWalletKey,
+              poolWallet: poolWalletKey,
               thread: threadAddress,
               threadAuthority: threadAuthority,
               clockworkProgram: clockworkProvider.threadProgram.programId,